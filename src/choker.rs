@@ -0,0 +1,198 @@
+//! Deciding which peers may download from us: tit-for-tat with an optimistic unchoke.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::AbortHandle;
+
+use crate::swarm::{PeerStats, Swarm};
+
+/// How often the choker reconsiders who to unchoke.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The optimistic unchoke moves on every this many rechokes (30 s).
+const OPTIMISTIC_ROUNDS: u64 = 3;
+
+/// Unchokes the peers that give us the most, plus one picked at random so new peers get a chance
+/// to show what they can do.
+///
+/// While downloading, peers are ranked by how fast they upload to us; while seeding, by how fast
+/// we upload to them, which favours peers that can actually take the data.
+#[derive(Debug, Default)]
+pub struct Choker {
+    slots: usize,
+    rounds: u64,
+    optimistic: Option<SocketAddr>,
+    /// Each peer's byte counters at the previous rechoke.
+    last: HashMap<SocketAddr, (u64, u64)>,
+}
+
+impl Choker {
+    /// A choker that unchokes up to `slots` peers at a time, one of them optimistically.
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            ..Default::default()
+        }
+    }
+
+    /// Decides who is unchoked until the next rechoke, by updating the `unchoked` flag of every
+    /// peer.
+    pub(crate) fn rechoke(&mut self, peers: &mut HashMap<SocketAddr, PeerStats>, seeding: bool) {
+        let interested = self.ranked(peers, seeding);
+        let regular = self.slots.saturating_sub(1);
+        let mut unchoked: HashSet<SocketAddr> = interested
+            .iter()
+            .take(regular)
+            .map(|&(addr, _)| addr)
+            .collect();
+
+        let candidates: Vec<SocketAddr> = interested
+            .iter()
+            .map(|&(addr, _)| addr)
+            .filter(|addr| !unchoked.contains(addr))
+            .collect();
+        let keep = !self.rounds.is_multiple_of(OPTIMISTIC_ROUNDS)
+            && self
+                .optimistic
+                .is_some_and(|addr| candidates.contains(&addr));
+        if !keep {
+            self.optimistic = if candidates.is_empty() || self.slots == 0 {
+                None
+            } else {
+                Some(candidates[crate::random_u64() as usize % candidates.len()])
+            };
+        }
+        unchoked.extend(self.optimistic);
+
+        for (addr, stats) in peers.iter_mut() {
+            stats.unchoked = unchoked.contains(addr);
+        }
+        self.last = peers
+            .iter()
+            .map(|(&addr, stats)| (addr, (stats.downloaded, stats.uploaded)))
+            .collect();
+        self.rounds += 1;
+    }
+
+    /// The interested peers with the bytes they moved since the previous rechoke, fastest first.
+    fn ranked(
+        &self,
+        peers: &HashMap<SocketAddr, PeerStats>,
+        seeding: bool,
+    ) -> Vec<(SocketAddr, u64)> {
+        let mut interested: Vec<(SocketAddr, u64)> = peers
+            .iter()
+            .filter(|(_, stats)| stats.interested)
+            .map(|(&addr, stats)| {
+                let (downloaded, uploaded) = self.last.get(&addr).copied().unwrap_or_default();
+                // a peer that reconnected from the same address starts counting from zero again
+                let rate = if seeding {
+                    stats.uploaded.saturating_sub(uploaded)
+                } else {
+                    stats.downloaded.saturating_sub(downloaded)
+                };
+                (addr, rate)
+            })
+            .collect();
+        interested.sort_by_key(|&(_, rate)| std::cmp::Reverse(rate));
+        interested
+    }
+}
+
+/// Runs the choker for `swarm` every [`RECHOKE_INTERVAL`] until the returned task is dropped.
+pub(crate) fn start(swarm: Arc<Swarm>) -> ChokerTask {
+    let task = tokio::spawn(async move {
+        let mut choker = Choker::new(swarm.config.upload_slots);
+        let mut interval = tokio::time::interval(RECHOKE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let seeding = swarm.is_complete();
+            choker.rechoke(&mut swarm.peers.lock().expect("not poisoned"), seeding);
+            swarm.rechoked.send_replace(());
+        }
+    });
+    ChokerTask(task.abort_handle())
+}
+
+/// Stops the choker when dropped.
+pub(crate) struct ChokerTask(AbortHandle);
+
+impl Drop for ChokerTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[test]
+fn choker_unchokes_fastest_and_one_optimistic() {
+    let addr = |port| SocketAddr::new([10, 0, 0, 1].into(), port);
+    let mut peers: HashMap<SocketAddr, PeerStats> = (1..=6)
+        .map(|port| {
+            let stats = PeerStats {
+                downloaded: u64::from(port) * 1000,
+                uploaded: u64::from(7 - port) * 1000,
+                interested: port != 6,
+                ..PeerStats::default()
+            };
+            (addr(port), stats)
+        })
+        .collect();
+
+    let mut choker = Choker::new(3);
+    choker.rechoke(&mut peers, false);
+    let unchoked = |peers: &HashMap<SocketAddr, PeerStats>| {
+        let mut unchoked: Vec<u16> = peers
+            .iter()
+            .filter(|(_, stats)| stats.unchoked)
+            .map(|(addr, _)| addr.port())
+            .collect();
+        unchoked.sort();
+        unchoked
+    };
+    // 6 downloads fastest but is not interested, so 5 and 4 get the regular slots
+    let first = unchoked(&peers);
+    assert_eq!(first.len(), 3);
+    assert!(first.contains(&4) && first.contains(&5));
+    let optimistic = choker.optimistic.unwrap();
+    assert!([1, 2, 3].contains(&optimistic.port()));
+
+    // the optimistic unchoke survives rechokes for a while, even though it is slow
+    for stats in peers.values_mut() {
+        stats.downloaded *= 2;
+    }
+    choker.rechoke(&mut peers, false);
+    assert_eq!(unchoked(&peers), first);
+
+    // while seeding, the peers we upload to fastest win
+    for stats in peers.values_mut() {
+        stats.uploaded *= 3;
+    }
+    choker.rechoke(&mut peers, true);
+    let seeding = unchoked(&peers);
+    assert!(seeding.contains(&1) && seeding.contains(&2));
+    assert!(!seeding.contains(&6));
+
+    // a peer that reconnects has its counters reset, which doesn't make it the fastest
+    for stats in peers.values_mut() {
+        stats.downloaded += 1000;
+        stats.uploaded += 1000;
+    }
+    let reconnected = peers.get_mut(&addr(5)).unwrap();
+    reconnected.downloaded = 0;
+    reconnected.uploaded = 0;
+    for seeding in [true, false] {
+        let ranked = choker.ranked(&peers, seeding);
+        assert_eq!(ranked.last(), Some(&(addr(5), 0)));
+        assert!(ranked.iter().all(|&(_, rate)| rate <= 1000));
+    }
+    choker.rechoke(&mut peers, false);
+    let regular: Vec<u16> = unchoked(&peers)
+        .into_iter()
+        .filter(|&port| choker.optimistic != Some(addr(port)))
+        .collect();
+    assert_eq!(regular.len(), 2);
+    assert!(!regular.contains(&5));
+}
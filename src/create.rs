@@ -0,0 +1,255 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    storage::Storage,
+    torrent::{File, Hashes, Info, Keys, Torrent},
+};
+
+const MIN_PIECE_LENGTH: usize = 1 << 14;
+const MAX_PIECE_LENGTH: usize = 1 << 24;
+/// Roughly how many pieces an automatically sized torrent should have.
+const TARGET_PIECES: usize = 1500;
+
+/// What to put in a new .torrent besides the content itself.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Tiers of tracker URLs. The first URL of the first tier becomes `announce`; if there is more
+    /// than one URL in total, all of them are also written to `announce-list`.
+    pub trackers: Vec<Vec<String>>,
+
+    /// The piece length to use; picked from the total size when `None`.
+    pub piece_length: Option<usize>,
+
+    pub private: bool,
+
+    pub comment: Option<String>,
+
+    pub created_by: Option<String>,
+
+    /// Defaults to the current time.
+    pub creation_date: Option<i64>,
+}
+
+/// Builds the metainfo for the file or directory at `path`, hashing its pieces in parallel.
+///
+/// A directory becomes a multi-file torrent named after the directory, containing every regular
+/// file below it in path order.
+pub async fn create(path: impl AsRef<Path>, opts: CreateOptions) -> anyhow::Result<Torrent> {
+    let path = tokio::fs::canonicalize(path.as_ref())
+        .await
+        .with_context(|| format!("resolve {}", path.as_ref().display()))?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("content must have a UTF-8 file name")?
+        .to_string();
+    let parent = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+
+    let announce = opts
+        .trackers
+        .iter()
+        .flatten()
+        .next()
+        .context("at least one tracker URL is required")?
+        .clone();
+    let announce_list = (opts.trackers.iter().flatten().count() > 1).then(|| {
+        opts.trackers
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect()
+    });
+
+    let metadata = tokio::fs::metadata(&path)
+        .await
+        .with_context(|| format!("stat {}", path.display()))?;
+    let (keys, length) = if metadata.is_dir() {
+        let files = walk(&path).await?;
+        anyhow::ensure!(!files.is_empty(), "{} contains no files", path.display());
+        let length = files.iter().map(|file| file.length).sum();
+        (Keys::MultiFile { files }, length)
+    } else {
+        let length = metadata.len() as usize;
+        (Keys::SingleFile { length }, length)
+    };
+    anyhow::ensure!(length > 0, "cannot create a torrent of empty content");
+
+    let plength = match opts.piece_length {
+        Some(plength) => {
+            anyhow::ensure!(
+                plength >= MIN_PIECE_LENGTH && plength.is_power_of_two(),
+                "piece length must be a power of two of at least {MIN_PIECE_LENGTH}"
+            );
+            plength
+        }
+        None => piece_length_for(length),
+    };
+
+    let creation_date = opts.creation_date.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0)
+    });
+
+    let mut t = Torrent {
+        announce,
+        announce_list,
+        comment: opts.comment,
+        created_by: opts.created_by,
+        creation_date: Some(creation_date),
+        nodes: None,
+        info: Info {
+            name,
+            plength,
+            pieces: Hashes(Vec::new()),
+            private: opts.private.then_some(1),
+            keys,
+        },
+        raw_info: None,
+    };
+
+    // a single file is stored at the given path, a directory tree under `<parent>/<name>`
+    let storage = match t.info.keys {
+        Keys::SingleFile { .. } => Storage::layout(&t, &path)?,
+        Keys::MultiFile { .. } => Storage::layout(&t, &parent)?,
+    };
+    t.info.pieces = hash_pieces(&storage).await?;
+    Ok(t)
+}
+
+/// Picks a power-of-two piece length that gives roughly [`TARGET_PIECES`] pieces.
+pub fn piece_length_for(length: usize) -> usize {
+    (length / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+async fn hash_pieces(storage: &Storage) -> anyhow::Result<Hashes> {
+    let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let hashes = futures_util::stream::iter(0..storage.npieces())
+        .map(|piece_i| async move {
+            let data = storage.read_piece(piece_i).await?;
+            let hash = tokio::task::spawn_blocking(move || -> [u8; 20] {
+                let mut hasher = Sha1::new();
+                hasher.update(&data);
+                hasher.finalize().into()
+            })
+            .await
+            .context("hash piece")?;
+            anyhow::Ok(hash)
+        })
+        .buffered(parallelism)
+        .collect::<Vec<_>>()
+        .await;
+    Ok(Hashes(hashes.into_iter().collect::<anyhow::Result<_>>()?))
+}
+
+/// Lists every regular file below `root`, sorted by path.
+async fn walk(root: &Path) -> anyhow::Result<Vec<File>> {
+    let mut found: Vec<(Vec<String>, usize)> = Vec::new();
+    let mut dirs: Vec<(PathBuf, Vec<String>)> = vec![(root.to_path_buf(), Vec::new())];
+    while let Some((dir, prefix)) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("list {}", dir.display()))?;
+        while let Some(entry) = entries.next_entry().await.context("read directory entry")? {
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|name| anyhow::anyhow!("file name {name:?} is not UTF-8"))?;
+            let mut path = prefix.clone();
+            path.push(name);
+
+            // follow symlinks, like everything else that reads the content will
+            let metadata = tokio::fs::metadata(entry.path())
+                .await
+                .with_context(|| format!("stat {}", entry.path().display()))?;
+            if metadata.is_dir() {
+                dirs.push((entry.path(), path));
+            } else if metadata.is_file() {
+                found.push((path, metadata.len() as usize));
+            }
+        }
+    }
+
+    found.sort();
+    Ok(found
+        .into_iter()
+        .map(|(path, length)| File { length, path })
+        .collect())
+}
+
+/// Writes `data` to `path` and makes a torrent of it that names a tracker nobody runs.
+#[cfg(test)]
+pub(crate) async fn create_test_torrent(
+    path: &Path,
+    data: &[u8],
+    piece_length: Option<usize>,
+) -> Torrent {
+    std::fs::write(path, data).unwrap();
+    let opts = CreateOptions {
+        trackers: vec![vec![String::from("http://127.0.0.1:1/announce")]],
+        piece_length,
+        ..Default::default()
+    };
+    create(path, opts).await.unwrap()
+}
+
+#[tokio::test]
+async fn create_multi_file_roundtrip() {
+    use crate::verify::verify;
+
+    let dir = tempfile::tempdir().unwrap();
+    let content = dir.path().join("content");
+    std::fs::create_dir_all(content.join("sub")).unwrap();
+    std::fs::write(content.join("b"), vec![1u8; 20_000]).unwrap();
+    std::fs::write(content.join("sub").join("a"), vec![2u8; 30_000]).unwrap();
+
+    let t = create(
+        &content,
+        CreateOptions {
+            trackers: vec![vec![String::from("http://tracker.example/announce")]],
+            private: true,
+            comment: Some(String::from("hello")),
+            creation_date: Some(1),
+            ..CreateOptions::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(t.info.name, "content");
+    assert_eq!(t.info.plength, MIN_PIECE_LENGTH);
+    assert_eq!(
+        t.info.pieces.0.len(),
+        50_000usize.div_ceil(MIN_PIECE_LENGTH)
+    );
+    assert!(t.is_private());
+    assert!(t.announce_list.is_none());
+    let Keys::MultiFile { files } = &t.info.keys else {
+        panic!("expected a multi-file torrent");
+    };
+    assert_eq!(files[0].path, vec!["b"]);
+    assert_eq!(files[1].path, vec!["sub", "a"]);
+
+    // the written metainfo parses back and describes the content we hashed
+    let dot_torrent = dir.path().join("content.torrent");
+    t.write(&dot_torrent).await.unwrap();
+    let t2 = Torrent::read(&dot_torrent).await.unwrap();
+    assert_eq!(t2.info_hash(), t.info_hash());
+    assert_eq!(t2.comment.as_deref(), Some("hello"));
+    assert!(verify(&t2, dir.path()).await.unwrap().is_complete());
+}
+
+#[test]
+fn piece_length_bounds() {
+    assert_eq!(piece_length_for(1), MIN_PIECE_LENGTH);
+    assert_eq!(piece_length_for(1 << 30), 1 << 20);
+    assert_eq!(piece_length_for(usize::MAX / 2), MAX_PIECE_LENGTH);
+}
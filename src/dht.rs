@@ -0,0 +1,729 @@
+//! A Mainline DHT node (BEP 5), for finding peers without a tracker.
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::AbortHandle;
+
+use self::krpc::{Arguments, Message, Response};
+use self::routing::{distance, RoutingTable, K};
+
+pub mod krpc;
+pub mod routing;
+
+/// Node ids and info hashes share one 160-bit keyspace.
+pub type NodeId = [u8; 20];
+
+/// Well-known nodes to join the DHT through when we know no other nodes yet.
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// How many queries a lookup has in flight at once.
+const ALPHA: usize = 3;
+
+/// Tokens are handed out with a secret that changes this often, and the previous secret is still
+/// accepted, so a token stays valid for up to twice as long.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// Announced peers are forgotten after this long unless they announce again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Limits on the announced peers we store for others.
+const MAX_PEERS_PER_TORRENT: usize = 100;
+const MAX_TORRENTS: usize = 1000;
+
+const MAX_PACKET: usize = 1 << 16;
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// The address to receive DHT traffic on. If the port is taken, any free port is used.
+    pub bind: SocketAddrV4,
+
+    /// `host:port` of nodes to join the DHT through.
+    pub bootstrap: Vec<String>,
+
+    /// Where our node id and known nodes are kept between runs, so later runs can bootstrap
+    /// without the bootstrap nodes.
+    pub state_file: Option<PathBuf>,
+
+    /// How long to wait for a node to answer a query.
+    pub query_timeout: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddrV4::new([0, 0, 0, 0].into(), 6881),
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|s| s.to_string()).collect(),
+            state_file: std::env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".bittorrent-dht")),
+            query_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// What we keep in [`DhtConfig::state_file`].
+#[derive(Debug, Deserialize, Serialize)]
+struct State {
+    id: ByteBuf,
+    /// Compact node info of the good nodes in the routing table.
+    nodes: ByteBuf,
+}
+
+/// A DHT node. It answers queries from other nodes in the background for as long as it lives.
+pub struct Dht {
+    shared: Arc<Shared>,
+    receiver: AbortHandle,
+    config: DhtConfig,
+    /// Nodes remembered from the last run, to bootstrap from.
+    saved_nodes: Vec<SocketAddrV4>,
+}
+
+/// The parts of the node the receive loop needs too.
+struct Shared {
+    id: NodeId,
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    /// Queries awaiting a response, by transaction id.
+    pending: Mutex<HashMap<[u8; 2], Pending>>,
+    next_transaction: AtomicU16,
+    tokens: Mutex<Tokens>,
+    /// Peers that announced themselves to us, by info hash.
+    peers: Mutex<HashMap<NodeId, Vec<(SocketAddrV4, Instant)>>>,
+}
+
+/// A query awaiting a response: the node it was sent to and where to deliver the response.
+type Pending = (SocketAddrV4, oneshot::Sender<Message>);
+
+/// A node that answered during a lookup.
+#[derive(Debug, Clone)]
+struct Found {
+    id: NodeId,
+    addr: SocketAddrV4,
+    /// The token it handed out for announcing, for `get_peers` lookups.
+    token: Option<ByteBuf>,
+}
+
+impl Dht {
+    /// Starts a node on `config.bind`. Call [`Dht::bootstrap`] to join the network.
+    pub async fn bind(config: DhtConfig) -> anyhow::Result<Self> {
+        let state = match &config.state_file {
+            Some(path) => load_state(path).await,
+            None => None,
+        };
+        let id = state
+            .as_ref()
+            .and_then(|state| krpc::node_id(&state.id))
+            .unwrap_or_else(random_id);
+        let saved_nodes = state
+            .map(|state| {
+                krpc::decode_nodes(&state.nodes)
+                    .into_iter()
+                    .map(|(_, addr)| addr)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let socket = match UdpSocket::bind(config.bind).await {
+            Ok(socket) => socket,
+            Err(_) if config.bind.port() != 0 => {
+                UdpSocket::bind(SocketAddrV4::new(*config.bind.ip(), 0))
+                    .await
+                    .context("bind DHT socket")?
+            }
+            Err(e) => return Err(e).context("bind DHT socket"),
+        };
+
+        let shared = Arc::new(Shared {
+            id,
+            socket,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(crate::random_u64() as u16),
+            tokens: Mutex::new(Tokens::new()),
+            peers: Mutex::new(HashMap::new()),
+        });
+        let receiver = tokio::spawn(receive(Arc::clone(&shared))).abort_handle();
+        Ok(Self {
+            shared,
+            receiver,
+            config,
+            saved_nodes,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.shared.id
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        self.shared
+            .socket
+            .local_addr()
+            .context("get DHT socket address")
+    }
+
+    /// The number of nodes in our routing table.
+    pub fn known_nodes(&self) -> usize {
+        self.shared.table.lock().expect("not poisoned").len()
+    }
+
+    /// Joins the network through the nodes we knew last time and the configured bootstrap nodes.
+    pub async fn bootstrap(&self) -> anyhow::Result<()> {
+        let mut hosts: Vec<String> = self
+            .saved_nodes
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        hosts.extend(self.config.bootstrap.iter().cloned());
+        self.bootstrap_from(&hosts).await
+    }
+
+    /// Contacts the nodes at `hosts` (`host:port`), then looks up our own id to fill the routing
+    /// table with the nodes around us.
+    pub async fn bootstrap_from(&self, hosts: &[String]) -> anyhow::Result<()> {
+        let resolved = futures_util::future::join_all(
+            hosts
+                .iter()
+                .map(|host| tokio::net::lookup_host(host.as_str())),
+        )
+        .await;
+        let mut addrs = Vec::new();
+        for addr in resolved.into_iter().flatten().flatten() {
+            if let SocketAddr::V4(addr) = addr {
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+
+        futures_util::future::join_all(addrs.iter().map(|&addr| self.ping(addr))).await;
+        anyhow::ensure!(self.known_nodes() > 0, "no DHT node answered");
+        self.find_node(self.shared.id).await;
+        Ok(())
+    }
+
+    /// Asks the node at `addr` whether it is alive, returning its id.
+    pub async fn ping(&self, addr: SocketAddrV4) -> anyhow::Result<NodeId> {
+        let response = self.query(addr, krpc::PING, self.arguments()).await?;
+        krpc::node_id(&response.id).context("node sent a malformed id")
+    }
+
+    /// Looks up the nodes closest to `target`.
+    pub async fn find_node(&self, target: NodeId) -> Vec<(NodeId, SocketAddrV4)> {
+        let (found, _) = self.lookup(target, krpc::FIND_NODE).await;
+        found.into_iter().map(|node| (node.id, node.addr)).collect()
+    }
+
+    /// Looks up peers in the swarm of `info_hash`.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddrV4> {
+        self.lookup(info_hash, krpc::GET_PEERS).await.1
+    }
+
+    /// Looks up peers in the swarm of `info_hash` and then announces that we are in it too,
+    /// accepting connections on `port` (or, if `None`, the port of our DHT socket).
+    pub async fn announce(&self, info_hash: [u8; 20], port: Option<u16>) -> Vec<SocketAddrV4> {
+        let (found, peers) = self.lookup(info_hash, krpc::GET_PEERS).await;
+        let announces = found.into_iter().filter_map(|node| {
+            let arguments = Arguments {
+                info_hash: Some(ByteBuf::from(info_hash)),
+                port: Some(port.unwrap_or(0)),
+                token: Some(node.token?),
+                implied_port: Some(u8::from(port.is_none())),
+                ..self.arguments()
+            };
+            Some(self.query(node.addr, krpc::ANNOUNCE_PEER, arguments))
+        });
+        for result in futures_util::future::join_all(announces).await {
+            if let Err(e) = result {
+                eprintln!("DHT announce failed: {e:#}");
+            }
+        }
+        peers
+    }
+
+    /// Remembers our id and the good nodes we know in [`DhtConfig::state_file`], if set.
+    pub async fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.config.state_file else {
+            return Ok(());
+        };
+        let nodes: Vec<_> = self
+            .shared
+            .table
+            .lock()
+            .expect("not poisoned")
+            .good_nodes()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        let state = State {
+            id: ByteBuf::from(self.shared.id),
+            nodes: krpc::encode_nodes(&nodes),
+        };
+        let bytes = serde_bencode::to_bytes(&state).context("encode DHT state")?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, bytes)
+            .await
+            .context("write DHT state")?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .context("replace DHT state")?;
+        Ok(())
+    }
+
+    fn arguments(&self) -> Arguments {
+        Arguments {
+            id: ByteBuf::from(self.shared.id),
+            ..Default::default()
+        }
+    }
+
+    /// An iterative lookup: repeatedly queries the closest nodes not asked yet with `method`
+    /// (`find_node` or `get_peers`), learning of ever closer nodes, until the closest nodes that
+    /// answer have all been asked.
+    ///
+    /// Returns the closest nodes that answered and, for `get_peers`, the peers they knew of.
+    async fn lookup(&self, target: NodeId, method: &str) -> (Vec<Found>, Vec<SocketAddrV4>) {
+        #[derive(PartialEq)]
+        enum Asked {
+            Not,
+            Answered,
+            Failed,
+        }
+
+        let mut candidates: Vec<(NodeId, SocketAddrV4, Asked)> = self
+            .shared
+            .table
+            .lock()
+            .expect("not poisoned")
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr, Asked::Not))
+            .collect();
+        let mut found = Vec::new();
+        let mut peers = Vec::new();
+        loop {
+            candidates.sort_by_key(|(id, ..)| distance(id, &target));
+            let batch: Vec<usize> = candidates
+                .iter()
+                .enumerate()
+                .filter(|(_, (.., asked))| *asked != Asked::Failed)
+                .take(K)
+                .filter(|(_, (.., asked))| *asked == Asked::Not)
+                .map(|(i, _)| i)
+                .take(ALPHA)
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let queries = batch.iter().map(|&i| {
+                let mut arguments = self.arguments();
+                if method == krpc::GET_PEERS {
+                    arguments.info_hash = Some(ByteBuf::from(target));
+                } else {
+                    arguments.target = Some(ByteBuf::from(target));
+                }
+                self.query(candidates[i].1, method, arguments)
+            });
+            let responses = futures_util::future::join_all(queries).await;
+
+            for (i, response) in batch.into_iter().zip(responses) {
+                let Ok(response) = response else {
+                    candidates[i].2 = Asked::Failed;
+                    continue;
+                };
+                candidates[i].2 = Asked::Answered;
+                found.push(Found {
+                    id: candidates[i].0,
+                    addr: candidates[i].1,
+                    token: response.token,
+                });
+                for peer in response.values.iter().flatten() {
+                    if let Some(peer) = krpc::decode_peer(peer) {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                        }
+                    }
+                }
+                for (id, addr) in
+                    krpc::decode_nodes(response.nodes.as_ref().map_or(&[][..], |nodes| &nodes[..]))
+                {
+                    if id != self.shared.id
+                        && !candidates
+                            .iter()
+                            .any(|(c_id, c_addr, _)| *c_id == id || *c_addr == addr)
+                    {
+                        candidates.push((id, addr, Asked::Not));
+                    }
+                }
+            }
+        }
+
+        found.sort_by_key(|node| distance(&node.id, &target));
+        found.truncate(K);
+        (found, peers)
+    }
+
+    /// Sends query `method` to the node at `addr` and waits for its response.
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        method: &str,
+        arguments: Arguments,
+    ) -> anyhow::Result<Response> {
+        let t = self
+            .shared
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes();
+        let query = serde_bencode::to_bytes(&Message::query(&t, method, arguments))
+            .context("encode DHT query")?;
+
+        let (tx, rx) = oneshot::channel();
+        self.shared
+            .pending
+            .lock()
+            .expect("not poisoned")
+            .insert(t, (addr, tx));
+        let sent = self.shared.socket.send_to(&query, addr).await;
+        let reply = match sent {
+            Ok(_) => tokio::time::timeout(self.config.query_timeout, rx)
+                .await
+                .ok()
+                .and_then(Result::ok),
+            Err(_) => None,
+        };
+        self.shared.pending.lock().expect("not poisoned").remove(&t);
+
+        let Some(reply) = reply else {
+            self.shared.table.lock().expect("not poisoned").failed(addr);
+            anyhow::bail!("DHT node {addr} did not respond to {method}");
+        };
+        if let Some((code, message)) = reply.error_code() {
+            anyhow::bail!("DHT node {addr} answered {method} with error {code}: {message}");
+        }
+        let response = reply
+            .r
+            .with_context(|| format!("DHT node {addr} sent a response without values"))?;
+        let id = krpc::node_id(&response.id)
+            .with_context(|| format!("DHT node {addr} sent a malformed id"))?;
+        self.shared
+            .table
+            .lock()
+            .expect("not poisoned")
+            .insert(id, addr);
+        Ok(response)
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// Receives every datagram on the DHT socket: answers queries, and hands responses to the
+/// queries waiting for them.
+async fn receive(shared: Arc<Shared>) {
+    let mut buf = vec![0; MAX_PACKET];
+    loop {
+        let Ok((len, from)) = shared.socket.recv_from(&mut buf).await else {
+            // e.g. an ICMP port unreachable for an earlier query; nothing to do about it
+            continue;
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        let Ok(message) = serde_bencode::from_bytes::<Message>(&buf[..len]) else {
+            continue;
+        };
+
+        match message.y.as_str() {
+            "q" => {
+                let reply = shared.answer(from, &message);
+                if let Ok(reply) = serde_bencode::to_bytes(&reply) {
+                    let _ = shared.socket.send_to(&reply, from).await;
+                }
+            }
+            "r" | "e" => {
+                let Ok(t) = <[u8; 2]>::try_from(&message.t[..]) else {
+                    continue;
+                };
+                let mut pending = shared.pending.lock().expect("not poisoned");
+                if pending.get(&t).is_some_and(|(addr, _)| *addr == from) {
+                    let (_, tx) = pending.remove(&t).expect("just checked");
+                    let _ = tx.send(message);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Shared {
+    /// The reply to `query` from the node at `from`.
+    fn answer(&self, from: SocketAddrV4, query: &Message) -> Message {
+        let t = &query.t[..];
+        let Some(arguments) = &query.a else {
+            return Message::error(t, krpc::ERROR_PROTOCOL, "missing arguments");
+        };
+        let Some(id) = krpc::node_id(&arguments.id) else {
+            return Message::error(t, krpc::ERROR_PROTOCOL, "malformed id");
+        };
+
+        let mut response = Response {
+            id: ByteBuf::from(self.id),
+            ..Default::default()
+        };
+        match query.q.as_deref().unwrap_or_default() {
+            krpc::PING => {}
+            krpc::FIND_NODE => {
+                let Some(target) = arguments
+                    .target
+                    .as_ref()
+                    .and_then(|target| krpc::node_id(target))
+                else {
+                    return Message::error(t, krpc::ERROR_PROTOCOL, "missing target");
+                };
+                response.nodes = Some(self.closest_nodes(&target));
+            }
+            krpc::GET_PEERS => {
+                let Some(info_hash) = arguments
+                    .info_hash
+                    .as_ref()
+                    .and_then(|hash| krpc::node_id(hash))
+                else {
+                    return Message::error(t, krpc::ERROR_PROTOCOL, "missing info_hash");
+                };
+                response.token = Some(self.tokens.lock().expect("not poisoned").issue(from));
+                let peers = self.peers_for(&info_hash);
+                if !peers.is_empty() {
+                    response.values = Some(
+                        peers
+                            .into_iter()
+                            .map(|peer| ByteBuf::from(krpc::encode_peer(peer)))
+                            .collect(),
+                    );
+                }
+                response.nodes = Some(self.closest_nodes(&info_hash));
+            }
+            krpc::ANNOUNCE_PEER => {
+                let Some(info_hash) = arguments
+                    .info_hash
+                    .as_ref()
+                    .and_then(|hash| krpc::node_id(hash))
+                else {
+                    return Message::error(t, krpc::ERROR_PROTOCOL, "missing info_hash");
+                };
+                let valid = arguments.token.as_deref().is_some_and(|token| {
+                    self.tokens.lock().expect("not poisoned").check(from, token)
+                });
+                if !valid {
+                    return Message::error(t, krpc::ERROR_PROTOCOL, "bad token");
+                }
+                let port = if arguments.implied_port == Some(1) {
+                    from.port()
+                } else {
+                    match arguments.port {
+                        Some(port) if port != 0 => port,
+                        _ => return Message::error(t, krpc::ERROR_PROTOCOL, "missing port"),
+                    }
+                };
+                self.store_peer(info_hash, SocketAddrV4::new(*from.ip(), port));
+            }
+            _ => return Message::error(t, krpc::ERROR_METHOD_UNKNOWN, "Method Unknown"),
+        }
+
+        // whoever queries us is a node too
+        self.table.lock().expect("not poisoned").insert(id, from);
+        Message::response(t, response)
+    }
+
+    fn closest_nodes(&self, target: &NodeId) -> ByteBuf {
+        let nodes: Vec<_> = self
+            .table
+            .lock()
+            .expect("not poisoned")
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        krpc::encode_nodes(&nodes)
+    }
+
+    fn peers_for(&self, info_hash: &NodeId) -> Vec<SocketAddrV4> {
+        let peers = self.peers.lock().expect("not poisoned");
+        peers
+            .get(info_hash)
+            .into_iter()
+            .flatten()
+            .filter(|(_, announced)| announced.elapsed() < PEER_TTL)
+            .map(|&(peer, _)| peer)
+            .collect()
+    }
+
+    fn store_peer(&self, info_hash: NodeId, peer: SocketAddrV4) {
+        let mut peers = self.peers.lock().expect("not poisoned");
+        if !peers.contains_key(&info_hash) && peers.len() >= MAX_TORRENTS {
+            peers.retain(|_, swarm| {
+                swarm.retain(|(_, announced)| announced.elapsed() < PEER_TTL);
+                !swarm.is_empty()
+            });
+            if peers.len() >= MAX_TORRENTS {
+                return;
+            }
+        }
+
+        let swarm = peers.entry(info_hash).or_default();
+        swarm.retain(|(known, announced)| *known != peer && announced.elapsed() < PEER_TTL);
+        if swarm.len() < MAX_PEERS_PER_TORRENT {
+            swarm.push((peer, Instant::now()));
+        }
+    }
+}
+
+/// Hands out the tokens `announce_peer` queries must present: a hash of the querying node's IP
+/// address and a secret that rotates, so tokens expire and cannot be forged for other addresses.
+struct Tokens {
+    current: u64,
+    previous: u64,
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn new() -> Self {
+        Self {
+            current: crate::random_u64(),
+            previous: crate::random_u64(),
+            rotated: Instant::now(),
+        }
+    }
+
+    fn issue(&mut self, node: SocketAddrV4) -> ByteBuf {
+        self.rotate();
+        ByteBuf::from(token(self.current, node))
+    }
+
+    fn check(&mut self, node: SocketAddrV4, token_bytes: &[u8]) -> bool {
+        self.rotate();
+        token_bytes == token(self.current, node) || token_bytes == token(self.previous, node)
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = crate::random_u64();
+            self.rotated = Instant::now();
+        }
+    }
+}
+
+fn token(secret: u64, node: SocketAddrV4) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(secret.to_be_bytes());
+    hasher.update(node.ip().octets());
+    hasher.finalize().into()
+}
+
+fn random_id() -> NodeId {
+    let mut id = [0; 20];
+    for chunk in id.chunks_mut(8) {
+        chunk.copy_from_slice(&crate::random_u64().to_be_bytes()[..chunk.len()]);
+    }
+    id
+}
+
+async fn load_state(path: &Path) -> Option<State> {
+    let bytes = tokio::fs::read(path).await.ok()?;
+    serde_bencode::from_bytes(&bytes).ok()
+}
+
+#[cfg(test)]
+fn test_config() -> DhtConfig {
+    DhtConfig {
+        bind: SocketAddrV4::new([127, 0, 0, 1].into(), 0),
+        bootstrap: Vec::new(),
+        state_file: None,
+        query_timeout: Duration::from_millis(500),
+    }
+}
+
+#[tokio::test]
+async fn dht_finds_announced_peers() {
+    let mut nodes = Vec::new();
+    for _ in 0..6 {
+        nodes.push(Dht::bind(test_config()).await.unwrap());
+    }
+    let first = nodes[0].local_addr().unwrap().to_string();
+    for node in &nodes[1..] {
+        node.bootstrap_from(std::slice::from_ref(&first))
+            .await
+            .unwrap();
+    }
+    assert!(nodes[0].known_nodes() >= 5);
+
+    let info_hash = [7; 20];
+    assert!(nodes[5].get_peers(info_hash).await.is_empty());
+    nodes[3].announce(info_hash, Some(7000)).await;
+    nodes[4].announce(info_hash, None).await;
+    let port = nodes[4].local_addr().unwrap().port();
+
+    let peers = nodes[5].get_peers(info_hash).await;
+    assert!(
+        peers.contains(&"127.0.0.1:7000".parse().unwrap()),
+        "{peers:?}"
+    );
+    assert!(peers.contains(&SocketAddrV4::new([127, 0, 0, 1].into(), port)));
+}
+
+#[tokio::test]
+async fn dht_rejects_bad_tokens_and_persists_nodes() {
+    let dir = tempfile::tempdir().unwrap();
+    let seed = Dht::bind(test_config()).await.unwrap();
+    let SocketAddr::V4(seed_addr) = seed.local_addr().unwrap() else {
+        unreachable!("bound to IPv4");
+    };
+
+    let config = DhtConfig {
+        state_file: Some(dir.path().join("dht")),
+        ..test_config()
+    };
+    let dht = Dht::bind(config.clone()).await.unwrap();
+    assert_eq!(dht.ping(seed_addr).await.unwrap(), seed.id());
+
+    let forged = Arguments {
+        info_hash: Some(ByteBuf::from([7; 20])),
+        port: Some(7000),
+        token: Some(ByteBuf::from(&b"forged"[..])),
+        ..dht.arguments()
+    };
+    let err = dht
+        .query(seed_addr, krpc::ANNOUNCE_PEER, forged)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("bad token"), "{err}");
+    assert!(seed.get_peers([7; 20]).await.is_empty());
+
+    dht.save().await.unwrap();
+    let id = dht.id();
+    drop(dht);
+    let restored = Dht::bind(config).await.unwrap();
+    assert_eq!(restored.id(), id);
+    assert_eq!(restored.saved_nodes, vec![seed_addr]);
+    restored.bootstrap().await.unwrap();
+    assert_eq!(restored.known_nodes(), 1);
+}
@@ -0,0 +1,206 @@
+//! KRPC, the bencoded query/response protocol DHT nodes speak over UDP (BEP 5).
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+
+use super::NodeId;
+
+pub const PING: &str = "ping";
+pub const FIND_NODE: &str = "find_node";
+pub const GET_PEERS: &str = "get_peers";
+pub const ANNOUNCE_PEER: &str = "announce_peer";
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// One KRPC message. `y` says which of the query (`q` and `a`), response (`r`) or error (`e`)
+/// fields are set.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Message {
+    /// The transaction id, echoed back in the response.
+    pub t: ByteBuf,
+
+    /// `q` for queries, `r` for responses and `e` for errors.
+    pub y: String,
+
+    /// The name of the method being queried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+
+    /// The arguments to the query.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<Arguments>,
+
+    /// The return values of a response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<Response>,
+
+    /// A list of an error code and message.
+    ///
+    /// Kept as raw values since serde_bencode does not decode a list into a tuple correctly; see
+    /// [`Message::error_code`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<Vec<Value>>,
+}
+
+impl Message {
+    pub fn query(t: &[u8], method: &str, a: Arguments) -> Self {
+        Self {
+            t: ByteBuf::from(t),
+            y: String::from("q"),
+            q: Some(method.to_string()),
+            a: Some(a),
+            ..Default::default()
+        }
+    }
+
+    pub fn response(t: &[u8], r: Response) -> Self {
+        Self {
+            t: ByteBuf::from(t),
+            y: String::from("r"),
+            r: Some(r),
+            ..Default::default()
+        }
+    }
+
+    pub fn error(t: &[u8], code: i64, message: &str) -> Self {
+        Self {
+            t: ByteBuf::from(t),
+            y: String::from("e"),
+            e: Some(vec![
+                Value::Int(code),
+                Value::Bytes(message.as_bytes().to_vec()),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    /// The code and message of an error message.
+    pub fn error_code(&self) -> Option<(i64, String)> {
+        match self.e.as_deref()? {
+            [Value::Int(code), Value::Bytes(message), ..] => {
+                Some((*code, String::from_utf8_lossy(message).into_owned()))
+            }
+            [Value::Int(code)] => Some((*code, String::new())),
+            _ => Some((ERROR_GENERIC, String::from("malformed error"))),
+        }
+    }
+}
+
+/// The arguments of every query type; which ones are set depends on the method.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Arguments {
+    /// The querying node's id.
+    pub id: ByteBuf,
+
+    /// The id to find the closest nodes to (`find_node`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+
+    /// The torrent to find or announce peers for (`get_peers`, `announce_peer`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+
+    /// The port the announcing peer accepts connections on (`announce_peer`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// The token received from an earlier `get_peers` to the same node (`announce_peer`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+
+    /// If 1, use the source port of the query instead of `port` (`announce_peer`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+}
+
+/// The return values of every response type; which ones are set depends on the method.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Response {
+    /// The responding node's id.
+    pub id: ByteBuf,
+
+    /// Compact node info of the closest nodes the responder knows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+
+    /// Compact peer info of peers in the swarm (`get_peers`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+
+    /// Must be presented to announce to the responder later (`get_peers`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+/// Turns the 20 bytes of an id field into a [`NodeId`].
+pub fn node_id(bytes: &[u8]) -> Option<NodeId> {
+    bytes.try_into().ok()
+}
+
+/// Encodes nodes as "compact node info": the 20-byte id, then the IPv4 address and port.
+pub fn encode_nodes(nodes: &[(NodeId, SocketAddrV4)]) -> ByteBuf {
+    let mut compact = Vec::with_capacity(26 * nodes.len());
+    for (id, addr) in nodes {
+        compact.extend(id);
+        compact.extend(encode_peer(*addr));
+    }
+    ByteBuf::from(compact)
+}
+
+/// Decodes "compact node info", ignoring a trailing partial entry.
+pub fn decode_nodes(compact: &[u8]) -> Vec<(NodeId, SocketAddrV4)> {
+    compact
+        .chunks_exact(26)
+        .filter_map(|node| Some((node_id(&node[..20])?, decode_peer(&node[20..])?)))
+        .collect()
+}
+
+/// Encodes a peer as "compact peer info": the IPv4 address, then the port.
+pub fn encode_peer(addr: SocketAddrV4) -> [u8; 6] {
+    let mut compact = [0; 6];
+    compact[..4].copy_from_slice(&addr.ip().octets());
+    compact[4..].copy_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+pub fn decode_peer(compact: &[u8]) -> Option<SocketAddrV4> {
+    let compact: [u8; 6] = compact.try_into().ok()?;
+    Some(SocketAddrV4::new(
+        Ipv4Addr::new(compact[0], compact[1], compact[2], compact[3]),
+        u16::from_be_bytes([compact[4], compact[5]]),
+    ))
+}
+
+#[test]
+fn krpc_roundtrip() {
+    let query = Message::query(
+        b"aa",
+        GET_PEERS,
+        Arguments {
+            id: ByteBuf::from([1; 20]),
+            info_hash: Some(ByteBuf::from([2; 20])),
+            ..Default::default()
+        },
+    );
+    let encoded = serde_bencode::to_bytes(&query).unwrap();
+    assert!(encoded.starts_with(b"d1:ad2:id20:"), "{encoded:?}");
+    let decoded: Message = serde_bencode::from_bytes(&encoded).unwrap();
+    assert_eq!(decoded.q.as_deref(), Some(GET_PEERS));
+    assert_eq!(decoded.a.unwrap().info_hash.unwrap().as_ref(), &[2; 20]);
+
+    let error: Message =
+        serde_bencode::from_bytes(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+    assert_eq!(
+        error.error_code(),
+        Some((ERROR_GENERIC, String::from("A Generic Error Ocurred")))
+    );
+
+    let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+    let nodes = encode_nodes(&[([3; 20], addr)]);
+    assert_eq!(decode_nodes(&nodes), vec![([3; 20], addr)]);
+}
@@ -0,0 +1,162 @@
+//! The Kademlia routing table: known nodes, bucketed by their XOR distance from our own id.
+
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use super::NodeId;
+
+/// The number of nodes kept per bucket.
+pub const K: usize = 8;
+
+/// A node that has not been heard from for this long is questionable and may be replaced.
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// A node that failed to respond this many times in a row is bad and may be replaced.
+const BAD_AFTER_FAILURES: u32 = 2;
+
+/// A node that failed to respond this many times in a row is dropped outright.
+const DROP_AFTER_FAILURES: u32 = 5;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Node {
+    fn is_good(&self) -> bool {
+        self.failures < BAD_AFTER_FAILURES && self.last_seen.elapsed() < QUESTIONABLE_AFTER
+    }
+}
+
+/// Bucket `i` holds the nodes whose id shares exactly the first `i` bits with ours, so there is
+/// one bucket per possible distance and the buckets close to us are the ones that can fill up.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    /// Records that we heard from node `id` at `addr`.
+    ///
+    /// Known nodes are refreshed. New nodes are added if their bucket has room, or replace a node
+    /// in it that has gone bad; otherwise the bucket is full of good nodes and the new one is
+    /// ignored, since long-lived nodes are the most likely to stay around.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddrV4) {
+        let Some(bucket_i) = self.bucket_of(&id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[bucket_i];
+        if let Some(i) = bucket.iter().position(|node| node.id == id) {
+            let mut node = bucket.remove(i);
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            bucket.push(node);
+            return;
+        }
+
+        let node = Node {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(i) = bucket.iter().position(|node| !node.is_good()) {
+            bucket.remove(i);
+            bucket.push(node);
+        }
+    }
+
+    /// Records that the node at `addr` did not respond to a query.
+    pub fn failed(&mut self, addr: SocketAddrV4) {
+        for bucket in &mut self.buckets {
+            if let Some(i) = bucket.iter().position(|node| node.addr == addr) {
+                bucket[i].failures += 1;
+                if bucket[i].failures >= DROP_AFTER_FAILURES {
+                    bucket.remove(i);
+                }
+                return;
+            }
+        }
+    }
+
+    /// Up to `n` known nodes, closest to `target` first.
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().copied().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    /// Every known node that is currently considered good.
+    pub fn good_nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flatten().filter(|node| node.is_good())
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn bucket_of(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own, id);
+        let zeros = distance
+            .iter()
+            .position(|&b| b != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+}
+
+/// The XOR metric; compares like the big-endian number it represents.
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+#[test]
+fn routing_table_buckets() {
+    let addr = |port| SocketAddrV4::new([127, 0, 0, 1].into(), port);
+    let mut table = RoutingTable::new([0; 20]);
+    table.insert([0; 20], addr(1));
+    assert!(table.is_empty(), "we are not in our own table");
+
+    // all of these differ from our id in the first bit, so they share bucket 0
+    for i in 0..K as u8 + 2 {
+        let mut id = [0xff; 20];
+        id[19] = i;
+        table.insert(id, addr(u16::from(i)));
+    }
+    assert_eq!(table.len(), K);
+
+    // a node that keeps failing makes room for a new one
+    table.failed(addr(0));
+    table.failed(addr(0));
+    let mut newcomer = [0xff; 20];
+    newcomer[19] = 0x80;
+    table.insert(newcomer, addr(100));
+    assert_eq!(table.len(), K);
+    assert!(table.good_nodes().any(|node| node.id == newcomer));
+
+    let mut near = [0; 20];
+    near[19] = 1;
+    table.insert(near, addr(200));
+    let closest = table.closest(&[0; 20], 2);
+    assert_eq!(closest[0].id, near);
+    assert_eq!(closest.len(), 2);
+}
@@ -1,232 +1,614 @@
-use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use futures_util::StreamExt;
 use sha1::{Digest, Sha1};
+use tokio::sync::mpsc;
+use tokio::task::{AbortHandle, JoinSet};
 
 use crate::{
+    choker,
+    dht::Dht,
+    listener::{Listener, DEFAULT_PORT},
+    lsd::Lsd,
     peer::Peer,
-    piece::Piece,
-    torrent::{File, Keys, Torrent},
-    tracker::TrackerResponse,
+    pool::PeerPool,
+    resume::Resume,
+    scheduler::{Block, Scheduler},
+    smartban::SmartBan,
+    swarm::Swarm,
+    torrent::Torrent,
+    tracker::{Progress, TrackerTiers},
     BLOCK_MAX,
 };
 
-pub async fn download_all(t: &Torrent) -> anyhow::Result<Downloaded> {
+/// Downloads every piece of `t`, writing each one to the swarm's storage as soon as its hash
+/// checks out.
+///
+/// Every peer works on pieces of its own at the same time, and the blocks they deliver are
+/// assembled and verified here. A piece that fails its hash check is downloaded again, and peers
+/// found to have sent bad data are banned. Pieces that `resume` already has are skipped, and every
+/// newly stored piece is recorded in it. Peers come from the trackers and, unless the torrent is
+/// private, from `dht`, from `lsd` on the local network, and from the peers we are connected to.
+/// Trackers are announced to again on the interval they ask for, and trackers and DHT whenever we
+/// run low on peers; peers that go away are reconnected to later. Once the last piece is stored,
+/// or on Ctrl-C, the trackers are told that we are leaving.
+///
+/// While downloading, the pieces we have are served to the peers we download from, and peers
+/// that connect to us through `listener` are downloaded from too.
+pub async fn download_all(
+    t: &Torrent,
+    swarm: &Arc<Swarm>,
+    resume: &mut Resume,
+    dht: Option<&Dht>,
+    listener: Option<&Listener>,
+    lsd: Option<&Lsd>,
+) -> anyhow::Result<()> {
+    if swarm.is_complete() {
+        return Ok(());
+    }
+
     let info_hash = t.info_hash();
-    let peer_info = TrackerResponse::query(t, info_hash)
-        .await
-        .context("query tracker for peer info")?;
-
-    let mut peer_list = Vec::new();
-    let mut peers = futures_util::stream::iter(peer_info.peers.0.iter())
-        .map(|&peer_addr| async move {
-            let peer = Peer::new(peer_addr, info_hash).await;
-            (peer_addr, peer)
-        })
-        .buffer_unordered(5);
-    while let Some((peer_addr, peer)) = peers.next().await {
-        match peer {
-            Ok(peer) => {
-                peer_list.push(peer);
-                if peer_list.len() >= 5 {
-                    break;
-                }
-            }
-            Err(e) => {
-                eprintln!("failed to connect to peer {peer_addr:?}: {e}");
-            }
+    let dht = dht.filter(|_| !t.is_private());
+    if let (Some(dht), Some(nodes)) = (dht, &t.nodes) {
+        let hosts: Vec<String> = nodes
+            .iter()
+            .map(|(host, port)| format!("{host}:{port}"))
+            .collect();
+        if let Err(e) = dht.bootstrap_from(&hosts).await {
+            eprintln!("failed to bootstrap from the torrent's DHT nodes: {e:#}");
         }
     }
-    drop(peers);
-
-    let mut peers = peer_list;
-    let mut need_pieces = BinaryHeap::new();
-    let mut no_peers = Vec::new();
-    for piece_i in 0..t.info.pieces.0.len() {
-        let piece = Piece::new(piece_i, &t, &peers);
-        if piece.peers().is_empty() {
-            no_peers.push(piece);
-        } else {
-            need_pieces.push(piece);
+    let incoming = listener.map(|listener| listener.register(Arc::clone(swarm)));
+    let _choker = choker::start(Arc::clone(swarm));
+    let port = listener.map_or(DEFAULT_PORT, Listener::port);
+    let local = lsd
+        .filter(|_| !t.is_private())
+        .map(|lsd| lsd.register(info_hash, port));
+    let mut trackers = TrackerTiers::new(t.trackers()).with_numwant(swarm.config.target_peers);
+    let peer_addrs = match find_peers(&mut trackers, dht, info_hash, swarm.progress(), port).await {
+        Ok(found) => found,
+        // peers on the local network may still turn up
+        Err(e) if local.is_some() => {
+            eprintln!("{e:#}");
+            Vec::new()
         }
-    }
+        Err(e) => return Err(e),
+    };
 
-    // TODO:
-    assert!(no_peers.is_empty());
-
-    // TODO: this is dumb because all the pieces for a given torrent may not fit in memory!
-    // should probably write every piece to disk so that we can also resume downloads, and seed
-    // later on.
-    let mut all_pieces = vec![0; t.length()];
-    while let Some(piece) = need_pieces.pop() {
-        let piece_size = piece.length();
-
-        // the + (BLOCK_MAX - 1) rounds up
-        let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-        let peers = peers
-            .iter_mut()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
-            .collect::<Vec<&mut Peer>>();
-
-        let (submit, tasks) = kanal::bounded_async(nblocks);
-        for block in 0..nblocks {
-            submit
-                .send(block)
-                .await
-                .expect("bound holds all these items");
-        }
+    let mut pool = PeerPool::new(swarm.config.target_peers);
+    pool.add(peer_addrs, Instant::now());
+    let sources = (!trackers.is_empty() || dht.is_some()).then_some(Sources {
+        trackers,
+        dht,
+        port,
+    });
+    download_from(t, swarm, resume, pool, sources, incoming, local).await
+}
 
-        let (finish, mut done) = tokio::sync::mpsc::channel(nblocks);
-        let mut participants = futures_util::stream::futures_unordered::FuturesUnordered::new();
-        for peer in peers {
-            participants.push(peer.participate(
-                piece.index(),
-                piece_size,
-                nblocks,
-                submit.clone(),
-                tasks.clone(),
-                finish.clone(),
-            ));
-        }
-        drop(submit);
-        drop(finish);
-        drop(tasks);
-
-        // TODO: this is dumb. at the very least, use bytes::Bytes to avoid the single large
-        // allocation and having to memcpy into it.
-        let mut all_blocks = vec![0u8; piece_size];
-        let mut bytes_received = 0;
-        loop {
+/// How long connecting to a peer, and the handshake with it, may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The least time between two announces made because we ran low on peers.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long waiting for an announce in flight, and telling the trackers that we completed, may
+/// take when we are done.
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to look for more peers when the pool runs low.
+struct Sources<'a> {
+    trackers: TrackerTiers,
+    dht: Option<&'a Dht>,
+    /// The port we accept connections on.
+    port: u16,
+}
+
+/// An announce in progress, which hands the sources back when it is done.
+type Announce<'a> =
+    Pin<Box<dyn Future<Output = (Sources<'a>, anyhow::Result<Vec<SocketAddr>>)> + 'a>>;
+
+/// Downloads every piece `swarm` lacks from the peers in `pool`, and from the peers that arrive
+/// on `incoming`. Peers found on the local network arrive on `local`, and join the pool.
+///
+/// Connections are kept up to the pool's target: peers that go away are retried later, and
+/// `sources` are asked for more peers whenever the pool runs low, and whenever the trackers'
+/// interval passes. When we are done, or interrupted with Ctrl-C, the trackers are told (see
+/// [`leave`]).
+async fn download_from<'a>(
+    t: &Torrent,
+    swarm: &Arc<Swarm>,
+    resume: &mut Resume,
+    mut pool: PeerPool,
+    mut sources: Option<Sources<'a>>,
+    mut incoming: Option<mpsc::Receiver<Peer>>,
+    mut local: Option<mpsc::Receiver<SocketAddr>>,
+) -> anyhow::Result<()> {
+    // pieces no connected peer has wait until one that has them connects, or sends Have
+    let lengths = (0..swarm.npieces())
+        .map(|piece_i| swarm.storage().piece_length(piece_i))
+        .collect();
+    let scheduler = Arc::new(Scheduler::new(lengths, &swarm.bitfield(), &swarm.config));
+    let (blocks_tx, mut blocks) = mpsc::channel::<Block>(64);
+    let mut connecting: JoinSet<(SocketAddr, anyhow::Result<Peer>)> = JoinSet::new();
+    let mut peers = JoinSet::new();
+    // the task of every peer we are downloading from, so it can be stopped if the peer is banned
+    let mut active = HashMap::new();
+    // where the peers that connected to us accept connections, to dial them there later
+    let mut listen_addrs = HashMap::new();
+    let mut announcing: Option<Announce<'a>> = None;
+    // the peers we were given were just announced for
+    let mut last_announce = Instant::now();
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let mut assembling: HashMap<usize, Assembly> = HashMap::new();
+    let mut smartban = SmartBan::new(swarm.config.max_hash_failures);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let result: anyhow::Result<()> = async {
+        while !scheduler.is_done() {
             tokio::select! {
-                joined = participants.next(), if !participants.is_empty() => {
-                    // if a participant ends early, it's either slow or failed
+                _ = &mut ctrl_c => anyhow::bail!("interrupted"),
+                block = blocks.recv() => {
+                    let block = block.expect("we hold a sender");
+                    let piece_i = block.piece_i;
+                    let piece_size = swarm.storage().piece_length(piece_i);
+                    let assembly = assembling.entry(piece_i).or_insert_with(|| Assembly {
+                        data: vec![0; piece_size],
+                        received: 0,
+                        contributors: vec![None; piece_size.div_ceil(BLOCK_MAX)],
+                    });
+                    assembly.data[block.begin..][..block.data.len()].copy_from_slice(&block.data);
+                    assembly.received += block.data.len();
+                    assembly.contributors[block.begin / BLOCK_MAX] = Some(block.peer);
+                    if assembly.received < piece_size {
+                        continue;
+                    }
+                    let Assembly { data, contributors, .. } =
+                        assembling.remove(&piece_i).expect("just updated");
+
+                    let hash = t.info.pieces.0[piece_i];
+                    let (data, actual) = tokio::task::spawn_blocking(move || {
+                        let mut hasher = Sha1::new();
+                        hasher.update(&data);
+                        let actual: [u8; 20] = hasher.finalize().into();
+                        (data, actual)
+                    })
+                    .await
+                    .context("hash piece")?;
+                    if actual != hash {
+                        let contributors: Vec<SocketAddr> = contributors.into_iter().flatten().collect();
+                        eprintln!("piece {piece_i} failed its hash check; downloading it again");
+                        for ip in smartban.failed(piece_i, &data, &contributors) {
+                            ban(swarm, &mut active, &mut pool, ip);
+                        }
+                        scheduler.failed(piece_i);
+                        continue;
+                    }
+                    for ip in smartban.verified(piece_i, &data) {
+                        ban(swarm, &mut active, &mut pool, ip);
+                    }
+
+                    swarm
+                        .storage()
+                        .write_piece(piece_i, &data)
+                        .await
+                        .with_context(|| format!("store piece {piece_i}"))?;
+                    resume
+                        .mark_piece(swarm.storage(), piece_i)
+                        .await
+                        .context("record piece in resume file")?;
+                    // tells every connection to send Have
+                    swarm.mark_have(piece_i);
+                    scheduler.finished(piece_i);
+                }
+                Some(joined) = peers.join_next() => {
                     match joined {
-                        None => {
-                            // there are no peers
-                            // this must mean we are about to get None from done.recv()
-                            // so we'll handle it there
+                        Ok((addr, result)) => {
+                            active.remove(&addr);
+                            if let Err(e) = result {
+                                eprintln!("peer {addr} failed: {e:#}");
+                            }
+                            // it went away before we were done, so it may be worth another try
+                            pool.failed(addr, Instant::now());
+                            if let Some(listen_addr) = listen_addrs.remove(&addr) {
+                                pool.add([listen_addr], Instant::now());
+                            }
                         }
-                        Some(Ok(_)) => {
-                            // the peer gave up because it timed out
-                            // nothing to do, except maybe de-prioritize this peer for later
-                            // TODO:
+                        // a banned peer, whose task we aborted
+                        Err(e) if e.is_cancelled() => {}
+                        Err(e) => eprintln!("peer task failed: {e}"),
+                    }
+                }
+                Some(connected) = connecting.join_next() => {
+                    let (addr, peer) = connected.context("connect task")?;
+                    match peer {
+                        Ok(peer) if !swarm.is_banned(addr.ip()) && pool.connected(addr, Instant::now()) => {
+                            spawn_download(&mut peers, &mut active, peer, &scheduler, &blocks_tx);
                         }
-                        Some(Err(_)) => {
-                            // the peer failed and should be removed
-                            // it already isn't participating in this piece any more, so this is
-                            // more of an indicator that we shouldn't try this peer again, and
-                            // should remove it from the global peer list
-                            // TODO:
+                        Ok(_) => {}
+                        Err(e) => {
+                            eprintln!("failed to connect to peer {addr}: {e:#}");
+                            pool.failed(addr, Instant::now());
                         }
                     }
-
                 }
-                piece = done.recv() => {
-                    if let Some(piece) = piece {
-                    // keep track of the bytes in message
-                        let piece = crate::peer::Piece::ref_from_bytes(&piece.payload[..])
-                            .expect("always get all Piece response fields from peer");
-                        all_blocks[piece.begin() as usize..][..piece.block().len()].copy_from_slice(piece.block());
-                        bytes_received += piece.block().len();
-                        if bytes_received == piece_size {
-                            // have received every piece
-                            // this must mean that all participation have either exited or are waiting
-                            // for more work -- in either case, it is okay to drop all the participant
-                            // futures.
-                            break;
+                Some(peer) = recv(&mut incoming) => {
+                    // we may have dialed the same peer already
+                    if pool.accepted(peer.addr, Instant::now()) {
+                        // where the peer accepts connections, if its extended handshake said
+                        if let Some(listen_addr) = swarm
+                            .update_peer(peer.addr, |stats| stats.listen_addr)
+                            .flatten()
+                            .filter(|&listen_addr| listen_addr != peer.addr)
+                        {
+                            listen_addrs.insert(peer.addr, listen_addr);
                         }
-                    } else {
-                        // there are no peers left, so we can't progress!
-                        break;
+                        spawn_download(&mut peers, &mut active, peer, &scheduler, &blocks_tx);
                     }
                 }
+                Some(addr) = recv(&mut local) => pool.add([addr], Instant::now()),
+                (returned, found) = async { announcing.as_mut().expect("guarded").await }, if announcing.is_some() => {
+                    announcing = None;
+                    sources = Some(returned);
+                    match found {
+                        Ok(found) => pool.add(found, Instant::now()),
+                        Err(e) => eprintln!("{e:#}"),
+                    }
+                }
+                _ = tick.tick() => {
+                    let now = Instant::now();
+                    resume
+                        .flush_if_due(swarm.storage())
+                        .await
+                        .context("record pieces in resume file")?;
+                    pool.add(swarm.take_discovered(), now);
+                    for addr in pool.to_connect(now) {
+                        if swarm.is_banned(addr.ip()) {
+                            pool.forget(addr);
+                            continue;
+                        }
+                        let swarm = Arc::clone(swarm);
+                        connecting.spawn(async move {
+                            let peer = tokio::time::timeout(CONNECT_TIMEOUT, Peer::new(addr, swarm))
+                                .await
+                                .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+                            (addr, peer)
+                        });
+                    }
+                    let due = sources.as_ref().is_some_and(|sources| {
+                        sources.trackers.announce_due(now)
+                            || (pool.needs_peers()
+                                && last_announce.elapsed() >= MIN_ANNOUNCE_INTERVAL
+                                && sources.trackers.may_announce(now))
+                    });
+                    if due {
+                        if let Some(mut found) = sources.take() {
+                            last_announce = Instant::now();
+                            let (info_hash, progress) = (swarm.info_hash, swarm.progress());
+                            announcing = Some(Box::pin(async move {
+                                let peers = find_peers(
+                                    &mut found.trackers,
+                                    found.dht,
+                                    info_hash,
+                                    progress,
+                                    found.port,
+                                )
+                                .await;
+                                (found, peers)
+                            }));
+                        }
+                    }
+                }
+            }
+            if active.is_empty()
+                && connecting.is_empty()
+                && pool.is_empty()
+                && sources.is_none()
+                && announcing.is_none()
+                && incoming.is_none()
+                && local.is_none()
+            {
+                // we'll need to find more peers to get the pieces we _didn't_ get
+                anyhow::bail!("no peers left to download from");
             }
         }
-        drop(participants);
-
-        if bytes_received == piece_size {
-            // great, we got all the bytes
-        } else {
-            // we'll need to connect to more peers, and make sure that those additional peers also
-            // have this piece, and then download the piece we _didn't_ get from them.
-            // probably also stick this back onto the pices_heap
-            anyhow::bail!("no peers left to get piece {}", piece.index());
+
+        let endgame = scheduler.endgame_stats();
+        if endgame.duplicate_requests > 0 {
+            eprintln!(
+                "endgame: {} duplicate requests, {} bytes wasted",
+                endgame.duplicate_requests, endgame.wasted_bytes
+            );
         }
+        Ok(())
+    }
+    .await;
 
-        let mut hasher = Sha1::new();
-        hasher.update(&all_blocks);
-        let hash: [u8; 20] = hasher.finalize().try_into().expect("");
-        assert_eq!(hash, piece.hash());
+    // whether we are done or not, the pieces we got since the last flush are worth keeping
+    let flushed = resume
+        .flush(swarm.storage())
+        .await
+        .context("record pieces in resume file");
+    leave(swarm, announcing, sources, result.is_ok()).await;
+    result.and(flushed)
+}
 
-        all_pieces[piece.index() * t.info.plength..][..piece_size].copy_from_slice(&all_blocks);
+/// Tells the trackers that we completed the download, if we did, and that we are leaving the
+/// swarm. An announce still in flight is waited for, since it has the trackers.
+async fn leave<'a>(
+    swarm: &Swarm,
+    announcing: Option<Announce<'a>>,
+    sources: Option<Sources<'a>>,
+    completed: bool,
+) {
+    let sources = match announcing {
+        Some(announcing) => tokio::time::timeout(LEAVE_TIMEOUT, announcing)
+            .await
+            .ok()
+            .map(|(sources, _)| sources),
+        None => sources,
+    };
+    let Some(mut sources) = sources else {
+        return;
+    };
+    if sources.trackers.is_empty() {
+        return;
     }
-
-    Ok(Downloaded {
-        bytes: all_pieces,
-        files: match &t.info.keys {
-            Keys::SingleFile { length } => vec![File {
-                length: *length,
-                path: vec![t.info.name.clone()],
-            }],
-            Keys::MultiFile { files } => files.clone(),
-        },
-    })
+    if completed {
+        let completed = sources
+            .trackers
+            .completed(swarm.info_hash, swarm.progress(), sources.port);
+        match tokio::time::timeout(LEAVE_TIMEOUT, completed).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("{e:#}"),
+            Err(_) => eprintln!("gave up telling the trackers that we completed"),
+        }
+    }
+    sources
+        .trackers
+        .stopped(swarm.info_hash, swarm.progress(), sources.port)
+        .await;
 }
 
-pub struct Downloaded {
-    bytes: Vec<u8>,
-    files: Vec<File>,
+/// A piece whose blocks are arriving.
+struct Assembly {
+    data: Vec<u8>,
+    /// How many bytes of `data` have arrived.
+    received: usize,
+    /// Which peer sent each block.
+    contributors: Vec<Option<SocketAddr>>,
 }
 
-impl<'a> IntoIterator for &'a Downloaded {
-    type Item = DownloadedFile<'a>;
-    type IntoIter = DownloadedIter<'a>;
+/// A peer being downloaded from, whose pieces are counted in the scheduler until it goes away,
+/// however it goes away.
+struct Downloading {
+    peer: Peer,
+    scheduler: Arc<Scheduler>,
+}
 
-    fn into_iter(self) -> Self::IntoIter {
-        DownloadedIter::new(self)
+impl Drop for Downloading {
+    fn drop(&mut self) {
+        self.scheduler
+            .remove_peer(self.peer.addr, &self.peer.bitfield);
     }
 }
 
-pub struct DownloadedIter<'d> {
-    downloaed: &'d Downloaded,
-    file_iter: std::slice::Iter<'d, File>,
-    offset: usize,
+/// Starts downloading from `peer` in the background, unless it is banned.
+fn spawn_download(
+    peers: &mut JoinSet<(SocketAddr, anyhow::Result<()>)>,
+    active: &mut HashMap<SocketAddr, AbortHandle>,
+    peer: Peer,
+    scheduler: &Arc<Scheduler>,
+    blocks: &mpsc::Sender<Block>,
+) {
+    if peer.swarm.is_banned(peer.addr.ip()) {
+        return;
+    }
+    let addr = peer.addr;
+    let blocks = blocks.clone();
+    scheduler.add_peer(&peer.bitfield);
+    let mut downloading = Downloading {
+        peer,
+        scheduler: Arc::clone(scheduler),
+    };
+    let task = peers.spawn(async move {
+        let result = downloading
+            .peer
+            .download(&downloading.scheduler, blocks)
+            .await;
+        (addr, result)
+    });
+    active.insert(addr, task);
 }
 
-impl<'d> DownloadedIter<'d> {
-    pub fn new(d: &'d Downloaded) -> Self {
-        Self {
-            downloaed: d,
-            file_iter: d.files.iter(),
-            offset: 0,
+/// Bans `ip` from the swarm, and disconnects the peers we have there.
+fn ban(
+    swarm: &Swarm,
+    active: &mut HashMap<SocketAddr, AbortHandle>,
+    pool: &mut PeerPool,
+    ip: IpAddr,
+) {
+    eprintln!("banning {ip} for sending bad data");
+    swarm.ban(ip);
+    active.retain(|&addr, task| {
+        if addr.ip() == ip {
+            task.abort();
+            pool.forget(addr);
         }
-    }
+        addr.ip() != ip
+    });
 }
 
-impl<'d> Iterator for DownloadedIter<'d> {
-    type Item = DownloadedFile<'d>;
+/// The next peer from `incoming`, if there is a channel to receive from.
+async fn recv<T>(incoming: &mut Option<mpsc::Receiver<T>>) -> Option<T> {
+    match incoming {
+        Some(incoming) => incoming.recv().await,
+        None => None,
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let file = self.file_iter.next()?;
-        let bytes = &self.downloaed.bytes[self.offset..][..file.length];
+/// Finds peers for `info_hash` by announcing to `trackers` and, if given, to the DHT that we
+/// accept connections on `port`, merging what they return. Trackers are told our `progress`.
+pub(crate) async fn find_peers(
+    trackers: &mut TrackerTiers,
+    dht: Option<&Dht>,
+    info_hash: [u8; 20],
+    progress: Progress,
+    port: u16,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let has_trackers = !trackers.is_empty();
+    let (from_trackers, from_dht) = tokio::join!(
+        async {
+            if has_trackers {
+                Some(trackers.announce(info_hash, progress, port).await)
+            } else {
+                None
+            }
+        },
+        async {
+            match dht {
+                Some(dht) => dht.announce(info_hash, Some(port)).await,
+                None => Vec::new(),
+            }
+        }
+    );
 
-        Some(DownloadedFile { file, bytes })
+    let mut peers = match from_trackers {
+        Some(Ok(response)) => response.peers.0,
+        Some(Err(e)) => {
+            eprintln!("{e:#}");
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+    for peer in from_dht.into_iter().map(SocketAddr::V4) {
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
     }
+    anyhow::ensure!(!peers.is_empty(), "found no peers through trackers or DHT");
+    Ok(peers)
 }
 
-pub struct DownloadedFile<'d> {
-    pub file: &'d File,
-    pub bytes: &'d [u8],
+/// Seeds `t` from the data at `path`, claiming to have the pieces in `have`, to peers that
+/// connect to `ip`.
+#[cfg(test)]
+async fn spawn_seed(
+    t: &Torrent,
+    path: &std::path::Path,
+    have: crate::peer::Bitfield,
+    ip: IpAddr,
+) -> (Listener, SocketAddr) {
+    use crate::storage::Storage;
+    use crate::swarm::SwarmConfig;
+
+    let storage = Storage::existing(t, path).unwrap();
+    let seed = Arc::new(Swarm::new(t, storage, have, SwarmConfig::default()).unwrap());
+    let listener = Listener::bind(SocketAddr::new(ip, 0)).await.unwrap();
+    let mut seeds = listener.register(seed);
+    tokio::spawn(async move {
+        while let Some(mut peer) = seeds.recv().await {
+            tokio::spawn(async move { peer.seed().await });
+        }
+    });
+    let addr = SocketAddr::new(ip, listener.port());
+    (listener, addr)
 }
 
-impl<'d> DownloadedFile<'d> {
-    pub fn path(&self) -> &'d [String] {
-        &self.file.path
-    }
+#[tokio::test]
+async fn download_assembles_pieces_from_peers() {
+    use crate::create::create_test_torrent;
+    use crate::storage::Storage;
+    use crate::swarm::SwarmConfig;
 
-    pub fn bytes(&self) -> &'d [u8] {
-        self.bytes
-    }
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data");
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7) as u8).collect();
+    let t = create_test_torrent(&path, &data, Some(1 << 15)).await;
+    let have = crate::verify::verify(&t, &path).await.unwrap().bitfield();
+    let (_listener, seed_addr) = spawn_seed(&t, &path, have, IpAddr::from([127, 0, 0, 1])).await;
+
+    // a leecher that may only work on one piece at a time
+    let output = dir.path().join("out");
+    let storage = Storage::open(&t, &output).await.unwrap();
+    let mut resume = Resume::load(&t, &storage).await.unwrap();
+    let config = SwarmConfig {
+        max_partial_pieces: 1,
+        ..SwarmConfig::default()
+    };
+    let swarm = Arc::new(Swarm::new(&t, storage, resume.bitfield().clone(), config).unwrap());
+    let mut pool = PeerPool::new(swarm.config.target_peers);
+    pool.add([seed_addr], Instant::now());
+    download_from(&t, &swarm, &mut resume, pool, None, None, None)
+        .await
+        .unwrap();
+
+    assert!(swarm.is_complete());
+    assert!(resume.has_piece(t.info.pieces.0.len() - 1));
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+}
+
+#[tokio::test]
+async fn download_recovers_from_bad_data_and_bans_its_sender() {
+    use crate::create::create_test_torrent;
+    use crate::storage::Storage;
+    use crate::swarm::SwarmConfig;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data");
+    let data: Vec<u8> = (0..50_000u32).map(|i| (i * 3) as u8).collect();
+    let t = create_test_torrent(&path, &data, Some(1 << 15)).await;
+    let have = crate::verify::verify(&t, &path).await.unwrap().bitfield();
+    let (_honest_listener, honest_addr) =
+        spawn_seed(&t, &path, have.clone(), IpAddr::from([127, 0, 0, 1])).await;
+
+    // a seed that claims every piece, but whose copy of the first one is corrupt
+    let bad_path = dir.path().join("bad");
+    let mut bad = data.clone();
+    bad[100] ^= 0xff;
+    std::fs::write(&bad_path, &bad).unwrap();
+    let (_liar_listener, liar_addr) =
+        spawn_seed(&t, &bad_path, have, IpAddr::from([127, 0, 0, 2])).await;
+
+    let output = dir.path().join("out");
+    let storage = Storage::open(&t, &output).await.unwrap();
+    let mut resume = Resume::load(&t, &storage).await.unwrap();
+    let swarm = Arc::new(
+        Swarm::new(
+            &t,
+            storage,
+            resume.bitfield().clone(),
+            SwarmConfig::default(),
+        )
+        .unwrap(),
+    );
+
+    // only the liar is around until it has failed the first piece often enough to be banned
+    let mut pool = PeerPool::new(swarm.config.target_peers);
+    pool.add([liar_addr], Instant::now());
+    let (late, incoming) = mpsc::channel(1);
+    let late_swarm = Arc::clone(&swarm);
+    let honest = tokio::spawn(async move {
+        while !late_swarm.is_banned(liar_addr.ip()) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let honest = Peer::new(honest_addr, late_swarm).await.unwrap();
+        late.send(honest).await.unwrap();
+        // keep the channel open until the download is done
+        late
+    });
+    download_from(&t, &swarm, &mut resume, pool, None, Some(incoming), None)
+        .await
+        .unwrap();
+    drop(honest.await.unwrap());
+
+    assert!(swarm.is_banned(liar_addr.ip()));
+    assert!(!swarm.is_banned(honest_addr.ip()));
+    assert_eq!(std::fs::read(&output).unwrap(), data);
 }
@@ -0,0 +1,354 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::peer::{Message, MessageTag};
+
+/// The extended message id of the extended handshake itself.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The extended message id we ask peers to use when sending us `ut_metadata` messages.
+pub const UT_METADATA_ID: u8 = 1;
+
+/// Metadata is exchanged in pieces of this size (the last one may be shorter).
+pub const METADATA_PIECE_SIZE: usize = 1 << 14;
+
+/// How many outstanding requests we are willing to queue for a peer, advertised as `reqq`.
+pub const REQQ: usize = 250;
+
+/// The payload of the extended handshake (BEP 10).
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ExtendedHandshake {
+    /// Maps the names of supported extensions to the extended message id the sender wants to
+    /// receive them with. An id of 0 means the extension is not (or no longer) supported.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+
+    /// The size of the info dictionary in bytes, if the sender has it (BEP 9).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+
+    /// Client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+
+    /// The number of outstanding requests the sender will queue without dropping any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<usize>,
+
+    /// The receiver's IP address (4 or 16 bytes) as seen by the sender.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+
+    /// The sender's listen port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+}
+
+impl ExtendedHandshake {
+    /// The id the other side wants to receive extension `name` with, if it supports it.
+    pub fn id_of(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|&id| id != 0)
+    }
+}
+
+/// An extension to the peer protocol that is negotiated by name through the extended handshake.
+pub trait Extension: Send + Sync {
+    /// The name the extension is advertised under in the `m` dictionary, e.g. `ut_metadata`.
+    fn name(&self) -> &'static str;
+
+    /// Lets the extension add its own keys (such as `metadata_size`) to our extended handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Handles the payload of an extended message `peer` sent for this extension.
+    ///
+    /// Returns payloads to send back to the peer under its id for this extension.
+    fn on_message(&self, peer: SocketAddr, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>>;
+}
+
+/// The extensions we support, and the extended message ids peers should use to reach them.
+#[derive(Default, Clone)]
+pub struct Registry {
+    /// Extension `i` is reached through extended message id `i + 1`; 0 is the handshake.
+    extensions: Vec<Arc<dyn Extension>>,
+}
+
+impl Registry {
+    /// Adds `extension`, returning the extended message id peers will use to address it.
+    pub fn register(&mut self, extension: Arc<dyn Extension>) -> u8 {
+        assert!(
+            self.extensions.len() < usize::from(u8::MAX),
+            "too many extensions"
+        );
+        self.extensions.push(extension);
+        self.extensions.len() as u8
+    }
+
+    /// The extension that a message with our extended message `id` is addressed to.
+    pub fn get(&self, id: u8) -> Option<&Arc<dyn Extension>> {
+        self.extensions.get(usize::from(id).checked_sub(1)?)
+    }
+
+    /// Our extended message id for the extension called `name`.
+    pub fn id_of(&self, name: &str) -> Option<u8> {
+        let i = self.extensions.iter().position(|ext| ext.name() == name)?;
+        Some(i as u8 + 1)
+    }
+
+    /// The extended handshake to send to a peer at `peer_ip`.
+    pub fn handshake(&self, peer_ip: IpAddr) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            m: self
+                .extensions
+                .iter()
+                .enumerate()
+                .map(|(i, ext)| (ext.name().to_string(), i as u8 + 1))
+                .collect(),
+            metadata_size: None,
+            v: Some(format!(
+                "{} {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )),
+            reqq: Some(REQQ),
+            yourip: Some(ByteBuf::from(match peer_ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            })),
+            p: None,
+        };
+        for ext in &self.extensions {
+            ext.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+}
+
+/// Serves the info dictionary to peers that ask for it with `ut_metadata` (BEP 9).
+pub struct UtMetadata {
+    info: Vec<u8>,
+}
+
+impl UtMetadata {
+    /// `info` is the bencoded info dictionary, exactly as hashed into the info hash.
+    pub fn new(info: Vec<u8>) -> Self {
+        Self { info }
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        handshake.metadata_size = Some(self.info.len());
+    }
+
+    fn on_message(&self, _peer: SocketAddr, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+        let n = bencode_len(payload).context("truncated ut_metadata message")?;
+        let msg: MetadataMessage =
+            serde_bencode::from_bytes(&payload[..n]).context("decode ut_metadata message")?;
+        if msg.msg_type != MetadataMessage::REQUEST {
+            // we never ask, so there is nothing to do with data or rejections
+            return Ok(Vec::new());
+        }
+
+        // the piece number comes from the peer, so it may be anything
+        let npieces = self.info.len().div_ceil(METADATA_PIECE_SIZE);
+        let start = msg
+            .piece
+            .checked_mul(METADATA_PIECE_SIZE)
+            .filter(|_| msg.piece < npieces);
+        let reply = if let Some(start) = start {
+            let mut reply = serde_bencode::to_bytes(&MetadataMessage {
+                msg_type: MetadataMessage::DATA,
+                piece: msg.piece,
+                total_size: Some(self.info.len()),
+            })
+            .context("encode ut_metadata data")?;
+            reply.extend(&self.info[start..][..METADATA_PIECE_SIZE.min(self.info.len() - start)]);
+            reply
+        } else {
+            serde_bencode::to_bytes(&MetadataMessage {
+                msg_type: MetadataMessage::REJECT,
+                piece: msg.piece,
+                total_size: None,
+            })
+            .context("encode ut_metadata reject")?
+        };
+        Ok(vec![reply])
+    }
+}
+
+/// A `ut_metadata` message (BEP 9). For `Data` messages the metadata piece follows the
+/// bencoded dictionary in the same extended message.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MetadataMessage {
+    pub msg_type: u8,
+
+    pub piece: usize,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    pub const REQUEST: u8 = 0;
+    pub const DATA: u8 = 1;
+    pub const REJECT: u8 = 2;
+
+    pub fn request(piece: usize) -> Self {
+        Self {
+            msg_type: Self::REQUEST,
+            piece,
+            total_size: None,
+        }
+    }
+}
+
+/// Wraps `payload` in an extended message with the given extended message id.
+pub fn message(id: u8, payload: &impl Serialize) -> anyhow::Result<Message> {
+    let bytes = serde_bencode::to_bytes(payload).context("encode extended message")?;
+    Ok(raw_message(id, &bytes))
+}
+
+/// Wraps already encoded `payload` bytes in an extended message with the given id.
+pub fn raw_message(id: u8, payload: &[u8]) -> Message {
+    let mut bytes = Vec::with_capacity(1 + payload.len());
+    bytes.push(id);
+    bytes.extend_from_slice(payload);
+    Message {
+        tag: MessageTag::Extended,
+        payload: bytes,
+    }
+}
+
+/// Splits the payload of an extended message into its id, bencoded dictionary, and whatever
+/// trailing bytes follow the dictionary.
+pub fn parse<'a, T: Deserialize<'a>>(payload: &'a [u8]) -> anyhow::Result<(u8, T, &'a [u8])> {
+    let (&id, rest) = payload.split_first().context("empty extended message")?;
+    let n = bencode_len(rest).context("truncated extended message")?;
+    let value = serde_bencode::from_bytes(&rest[..n]).context("decode extended message")?;
+    Ok((id, value, &rest[n..]))
+}
+
+/// The length of the bencoded value at the start of `data`, if it is complete.
+///
+/// Peers control the input, so nesting is tracked with a counter rather than by recursing.
+fn bencode_len(data: &[u8]) -> Option<usize> {
+    // lists and dictionaries opened but not yet closed
+    let mut depth = 0usize;
+    let mut i = 0;
+    loop {
+        match *data.get(i)? {
+            b'i' => i += data[i..].iter().position(|&b| b == b'e')? + 1,
+            b'l' | b'd' => {
+                depth += 1;
+                i += 1;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                i += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = i + data[i..].iter().position(|&b| b == b':')?;
+                let len: usize = std::str::from_utf8(&data[i..colon]).ok()?.parse().ok()?;
+                let end = (colon + 1).checked_add(len)?;
+                if end > data.len() {
+                    return None;
+                }
+                i = end;
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(i);
+        }
+    }
+}
+
+#[test]
+fn parse_metadata_data_message() {
+    let mut payload = vec![UT_METADATA_ID];
+    payload.extend(b"d8:msg_typei1e5:piecei0e10:total_sizei3ee");
+    payload.extend(b"abc");
+
+    let (id, msg, rest) = parse::<MetadataMessage>(&payload).unwrap();
+    assert_eq!(id, UT_METADATA_ID);
+    assert_eq!(msg.msg_type, MetadataMessage::DATA);
+    assert_eq!(msg.total_size, Some(3));
+    assert_eq!(rest, b"abc");
+}
+
+#[test]
+fn registry_serves_metadata() {
+    let info = vec![b'x'; METADATA_PIECE_SIZE + 10];
+    let mut registry = Registry::default();
+    let id = registry.register(Arc::new(UtMetadata::new(info.clone())));
+    assert_eq!(registry.id_of("ut_metadata"), Some(id));
+
+    let handshake = registry.handshake(IpAddr::from([127, 0, 0, 1]));
+    assert_eq!(handshake.id_of("ut_metadata"), Some(id));
+    assert_eq!(handshake.metadata_size, Some(info.len()));
+    assert_eq!(
+        handshake.yourip.as_deref().map(|ip| &ip[..]),
+        Some(&[127, 0, 0, 1][..])
+    );
+    let encoded = serde_bencode::to_bytes(&handshake).unwrap();
+    let decoded: ExtendedHandshake = serde_bencode::from_bytes(&encoded).unwrap();
+    assert_eq!(decoded.reqq, Some(REQQ));
+
+    let peer = SocketAddr::new([127, 0, 0, 1].into(), 6881);
+    let request = serde_bencode::to_bytes(&MetadataMessage::request(1)).unwrap();
+    let replies = registry
+        .get(id)
+        .unwrap()
+        .on_message(peer, &request)
+        .unwrap();
+    let mut reply = vec![id];
+    reply.extend(&replies[0]);
+    let (_, data, rest) = parse::<MetadataMessage>(&reply).unwrap();
+    assert_eq!(data.msg_type, MetadataMessage::DATA);
+    assert_eq!(rest.len(), 10);
+
+    let request = serde_bencode::to_bytes(&MetadataMessage::request(2)).unwrap();
+    let replies = registry
+        .get(id)
+        .unwrap()
+        .on_message(peer, &request)
+        .unwrap();
+    let msg: MetadataMessage = serde_bencode::from_bytes(&replies[0]).unwrap();
+    assert_eq!(msg.msg_type, MetadataMessage::REJECT);
+
+    // a piece number so big that its offset overflows
+    let request = serde_bencode::to_bytes(&MetadataMessage::request(1 << 60)).unwrap();
+    let replies = registry
+        .get(id)
+        .unwrap()
+        .on_message(peer, &request)
+        .unwrap();
+    let msg: MetadataMessage = serde_bencode::from_bytes(&replies[0]).unwrap();
+    assert_eq!(msg.msg_type, MetadataMessage::REJECT);
+}
+
+#[test]
+fn bencode_len_handles_deep_nesting() {
+    assert_eq!(bencode_len(b"d1:ai1e1:bl2:xyee3:end"), Some(17));
+    assert_eq!(bencode_len(b"li1e"), None);
+    assert_eq!(bencode_len(b"e"), None);
+
+    // about as deep as a single frame allows, which used to overflow the stack
+    let deep = vec![b'l'; 60_000];
+    assert_eq!(bencode_len(&deep), None);
+    let mut nested = deep.clone();
+    nested.extend(vec![b'e'; 60_000]);
+    assert_eq!(bencode_len(&nested), Some(120_000));
+    let mut payload = vec![UT_METADATA_ID];
+    payload.extend(&deep);
+    assert!(parse::<MetadataMessage>(&payload).is_err());
+}
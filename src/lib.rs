@@ -1,7 +1,36 @@
+pub mod choker;
+pub mod create;
+pub mod dht;
 pub mod download;
+pub mod extension;
+pub mod listener;
+pub mod lsd;
+pub mod magnet;
 pub mod peer;
-pub mod piece;
+pub mod pex;
+pub mod picker;
+pub mod pool;
+pub mod resume;
+pub mod scheduler;
+pub mod seed;
+pub mod smartban;
+pub mod storage;
+pub mod swarm;
 pub mod torrent;
 pub mod tracker;
+pub mod verify;
 
 pub const BLOCK_MAX: usize = 1 << 14;
+
+/// A fresh random number, drawn from the randomly keyed std hasher.
+///
+/// Good enough for transaction ids, tie-breaking and shuffling; not for anything cryptographic.
+pub(crate) fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+use crate::peer::{Handshake, Peer};
+use crate::swarm::Swarm;
+
+/// The port we listen on (and tell trackers about) unless it is taken.
+pub const DEFAULT_PORT: u16 = 6881;
+
+/// How long a connecting peer has to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts connections from peers on one port for any number of torrents.
+///
+/// A connecting peer is matched to a torrent by the info hash in its handshake; once the
+/// handshake is complete, the peer is handed to whoever registered that torrent.
+pub struct Listener {
+    port: u16,
+    swarms: Arc<Mutex<HashMap<[u8; 20], Registered>>>,
+    acceptor: AbortHandle,
+}
+
+/// A torrent that accepts peers, and where to send them.
+struct Registered {
+    swarm: Arc<Swarm>,
+    peers: mpsc::Sender<Peer>,
+}
+
+impl Listener {
+    /// Listens on `addr`. If its port is taken, any free port is used instead.
+    ///
+    /// On most systems, listening on the unspecified IPv6 address accepts IPv4 peers too.
+    pub async fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(_) if addr.port() != 0 => TcpListener::bind(SocketAddr::new(addr.ip(), 0))
+                .await
+                .context("bind peer listener")?,
+            Err(e) => return Err(e).context("bind peer listener"),
+        };
+        let port = listener
+            .local_addr()
+            .context("get peer listener address")?
+            .port();
+
+        let swarms = Arc::new(Mutex::new(HashMap::new()));
+        let acceptor = tokio::spawn(accept(listener, Arc::clone(&swarms))).abort_handle();
+        Ok(Self {
+            port,
+            swarms,
+            acceptor,
+        })
+    }
+
+    /// The port peers can reach us on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Starts accepting peers for `swarm`, which arrive (handshaken) on the returned channel.
+    ///
+    /// Dropping the receiver stops accepting peers for the torrent.
+    pub(crate) fn register(&self, swarm: Arc<Swarm>) -> mpsc::Receiver<Peer> {
+        let (tx, rx) = mpsc::channel(16);
+        self.swarms
+            .lock()
+            .expect("not poisoned")
+            .insert(swarm.info_hash, Registered { swarm, peers: tx });
+        rx
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.acceptor.abort();
+    }
+}
+
+async fn accept(listener: TcpListener, swarms: Arc<Mutex<HashMap<[u8; 20], Registered>>>) {
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            // e.g. out of file descriptors; the next accept may well succeed
+            continue;
+        };
+        // on a dual-stack socket, IPv4 peers show up as IPv4-mapped IPv6 addresses
+        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        let swarms = Arc::clone(&swarms);
+        tokio::spawn(async move {
+            if let Err(e) = handshake(stream, addr, &swarms).await {
+                eprintln!("incoming peer {addr} failed: {e:#}");
+            }
+        });
+    }
+}
+
+/// Reads the handshake of a peer that connected to us, and completes it if we have the torrent
+/// it asks for.
+async fn handshake(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    swarms: &Mutex<HashMap<[u8; 20], Registered>>,
+) -> anyhow::Result<()> {
+    let mut theirs = Handshake::new([0; 20], [0; 20]);
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(theirs.as_bytes_mut()))
+        .await
+        .context("peer did not send a handshake in time")?
+        .context("read handshake")?;
+    anyhow::ensure!(theirs.length == 19);
+    anyhow::ensure!(theirs.bittorent_protocol == *b"BitTorrent protocol");
+
+    let (swarm, peers) = {
+        let mut swarms = swarms.lock().expect("not poisoned");
+        // forget torrents nobody takes peers for any more
+        swarms.retain(|_, registered| !registered.peers.is_closed());
+        let registered = swarms
+            .get(&theirs.info_hash)
+            .context("peer asked for a torrent we do not have")?;
+        (Arc::clone(&registered.swarm), registered.peers.clone())
+    };
+    anyhow::ensure!(!swarm.is_banned(addr.ip()), "peer is banned");
+
+    let peer = Peer::accept(stream, addr, &theirs, swarm).await?;
+    peers
+        .send(peer)
+        .await
+        .ok()
+        .context("torrent stopped accepting peers")
+}
+
+/// Makes a torrent of `data` at `path` and a swarm for it that has none of the pieces.
+#[cfg(test)]
+async fn test_swarm(path: &std::path::Path, data: &[u8]) -> (crate::torrent::Torrent, Swarm) {
+    use crate::create::create_test_torrent;
+    use crate::peer::Bitfield;
+    use crate::storage::Storage;
+    use crate::swarm::SwarmConfig;
+
+    let t = create_test_torrent(path, data, None).await;
+    let storage = Storage::existing(&t, path).unwrap();
+    let swarm = Swarm::new(&t, storage, Bitfield::new(1), SwarmConfig::default()).unwrap();
+    (t, swarm)
+}
+
+#[tokio::test]
+async fn listener_routes_peers_by_info_hash() {
+    use tokio::io::AsyncWriteExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut receivers = Vec::new();
+    let listener = Listener::bind(SocketAddr::new([127, 0, 0, 1].into(), 0))
+        .await
+        .unwrap();
+    let mut info_hashes = Vec::new();
+    for name in ["a", "b"] {
+        let path = dir.path().join(name);
+        let (t, swarm) = test_swarm(&path, name.repeat(100).as_bytes()).await;
+        info_hashes.push(t.info_hash());
+        receivers.push(listener.register(Arc::new(swarm)));
+    }
+
+    let addr = SocketAddr::new([127, 0, 0, 1].into(), listener.port());
+    let connect = |info_hash| async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut handshake = Handshake::new(info_hash, [1; 20]);
+        stream.write_all(handshake.as_bytes_mut()).await.unwrap();
+        // say something so the listener need not wait for a bitfield
+        stream.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+        let mut reply = Handshake::new([0; 20], [0; 20]);
+        let read = stream.read_exact(reply.as_bytes_mut()).await;
+        (stream, read.map(|_| reply.info_hash))
+    };
+
+    let (_stream, reply) = connect(info_hashes[1]).await;
+    assert_eq!(reply.unwrap(), info_hashes[1]);
+    let peer = receivers[1].recv().await.unwrap();
+    assert!(peer.peer_interested);
+    assert!(receivers[0].try_recv().is_err());
+
+    // a torrent we do not have is hung up on
+    let (_stream, reply) = connect([9; 20]).await;
+    assert!(reply.is_err());
+
+    // as is one nobody takes peers for any more
+    drop(receivers.remove(0));
+    let (_stream, reply) = connect(info_hashes[0]).await;
+    assert!(reply.is_err());
+}
+
+#[tokio::test]
+async fn listener_accepts_ipv4_and_ipv6_peers_on_one_socket() {
+    use std::net::{IpAddr, Ipv6Addr};
+    use tokio::io::AsyncWriteExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (t, swarm) = test_swarm(&dir.path().join("data"), &[b'x'; 100]).await;
+    let listener = Listener::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0))
+        .await
+        .unwrap();
+    let mut peers = listener.register(Arc::new(swarm));
+
+    for ip in [IpAddr::from([127, 0, 0, 1]), Ipv6Addr::LOCALHOST.into()] {
+        let mut stream = TcpStream::connect((ip, listener.port())).await.unwrap();
+        let mut handshake = Handshake::new(t.info_hash(), [1; 20]);
+        stream.write_all(handshake.as_bytes_mut()).await.unwrap();
+        stream.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+        let peer = peers.recv().await.unwrap();
+        // IPv4 peers don't show up as IPv4-mapped IPv6 addresses
+        assert_eq!(peer.addr.ip(), ip);
+        assert_eq!(peer.addr, stream.local_addr().unwrap());
+    }
+}
@@ -0,0 +1,366 @@
+//! Local Service Discovery (BEP 14): finding peers on the local network by multicast, without a
+//! tracker.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::AbortHandle;
+
+/// The IPv4 multicast group LSD announces go to.
+pub const LSD_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+
+/// The IPv6 multicast group LSD announces go to.
+pub const LSD_GROUP6: SocketAddrV6 = SocketAddrV6::new(
+    Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f),
+    6771,
+    0,
+    0,
+);
+
+/// How often every torrent is announced again.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often the announcer checks for torrents that are due.
+const ANNOUNCE_CHECK: Duration = Duration::from_secs(60);
+
+/// Settings for local service discovery.
+#[derive(Debug, Clone)]
+pub struct LsdConfig {
+    /// The IPv4 group to announce to and listen on.
+    pub group: SocketAddrV4,
+    /// The IPv6 group to announce to and listen on, if any.
+    pub group6: Option<SocketAddrV6>,
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self {
+            group: LSD_GROUP,
+            group6: Some(LSD_GROUP6),
+        }
+    }
+}
+
+/// Announces our torrents to the local network, and hands out the peers on it that announce the
+/// same torrents.
+///
+/// Announces carry a random cookie, so that our own announces, which multicast loops back to us,
+/// are not mistaken for another peer's. If the LSD port is taken, e.g. by another client on the
+/// same machine, we still announce but can't hear other peers' announces.
+pub struct Lsd {
+    cookie: String,
+    torrents: Arc<Mutex<HashMap<[u8; 20], Registered>>>,
+    /// Wakes the announcer when a torrent is registered.
+    registered: Arc<Notify>,
+    tasks: Vec<AbortHandle>,
+}
+
+/// A torrent we announce, and where to send the peers found for it.
+struct Registered {
+    /// The port we accept peers for the torrent on.
+    port: u16,
+    peers: mpsc::Sender<SocketAddr>,
+    /// When we last announced the torrent.
+    announced: Option<Instant>,
+}
+
+impl Lsd {
+    /// Joins the multicast groups in `config`. Failing to join the IPv6 group is not an error,
+    /// since many networks have no IPv6.
+    pub async fn bind(config: LsdConfig) -> anyhow::Result<Self> {
+        let socket = bind(SocketAddr::from((
+            Ipv4Addr::UNSPECIFIED,
+            config.group.port(),
+        )))
+        .await
+        .context("bind LSD socket")?;
+        socket
+            .join_multicast_v4(*config.group.ip(), Ipv4Addr::UNSPECIFIED)
+            .context("join LSD multicast group")?;
+        let mut sockets = vec![(Arc::new(socket), SocketAddr::V4(config.group))];
+        if let Some(group6) = config.group6 {
+            match join6(group6).await {
+                Ok(socket) => sockets.push((Arc::new(socket), SocketAddr::V6(group6))),
+                Err(e) => eprintln!("LSD over IPv6 unavailable: {e:#}"),
+            }
+        }
+
+        let cookie = format!("{:016x}", crate::random_u64());
+        let torrents = Arc::new(Mutex::new(HashMap::new()));
+        let registered = Arc::new(Notify::new());
+        let mut tasks = Vec::new();
+        for (socket, _) in &sockets {
+            let receiver = receive(Arc::clone(socket), cookie.clone(), Arc::clone(&torrents));
+            tasks.push(tokio::spawn(receiver).abort_handle());
+        }
+        let announcer = announce(
+            sockets,
+            cookie.clone(),
+            Arc::clone(&torrents),
+            Arc::clone(&registered),
+        );
+        tasks.push(tokio::spawn(announcer).abort_handle());
+        Ok(Self {
+            cookie,
+            torrents,
+            registered,
+            tasks,
+        })
+    }
+
+    /// Starts announcing `info_hash`, with peers to connect to us on `port`, and returns the
+    /// peers on the local network that announce it.
+    ///
+    /// Dropping the receiver stops announcing the torrent.
+    pub fn register(&self, info_hash: [u8; 20], port: u16) -> mpsc::Receiver<SocketAddr> {
+        let (tx, rx) = mpsc::channel(16);
+        self.torrents.lock().expect("not poisoned").insert(
+            info_hash,
+            Registered {
+                port,
+                peers: tx,
+                announced: None,
+            },
+        );
+        self.registered.notify_one();
+        rx
+    }
+
+    /// The cookie that marks our announces.
+    pub fn cookie(&self) -> &str {
+        &self.cookie
+    }
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Binds `addr`, or any free port if its port is taken.
+async fn bind(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    match UdpSocket::bind(addr).await {
+        Ok(socket) => Ok(socket),
+        Err(_) if addr.port() != 0 => UdpSocket::bind(SocketAddr::new(addr.ip(), 0)).await,
+        Err(e) => Err(e),
+    }
+}
+
+async fn join6(group: SocketAddrV6) -> anyhow::Result<UdpSocket> {
+    let socket = bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, group.port())))
+        .await
+        .context("bind LSD socket")?;
+    socket
+        .join_multicast_v6(group.ip(), 0)
+        .context("join LSD multicast group")?;
+    Ok(socket)
+}
+
+/// Announces every registered torrent as soon as it is registered, and again every
+/// [`ANNOUNCE_INTERVAL`].
+async fn announce(
+    sockets: Vec<(Arc<UdpSocket>, SocketAddr)>,
+    cookie: String,
+    torrents: Arc<Mutex<HashMap<[u8; 20], Registered>>>,
+    registered: Arc<Notify>,
+) {
+    loop {
+        let announces: Vec<([u8; 20], u16)> = {
+            let now = Instant::now();
+            let mut torrents = torrents.lock().expect("not poisoned");
+            // forget torrents nobody takes peers for any more
+            torrents.retain(|_, registered| !registered.peers.is_closed());
+            torrents
+                .iter_mut()
+                .filter(|(_, registered)| {
+                    registered
+                        .announced
+                        .is_none_or(|at| now.duration_since(at) >= ANNOUNCE_INTERVAL)
+                })
+                .map(|(&info_hash, registered)| {
+                    registered.announced = Some(now);
+                    (info_hash, registered.port)
+                })
+                .collect()
+        };
+        for (socket, group) in &sockets {
+            for &(info_hash, port) in &announces {
+                let msg = Announce {
+                    port,
+                    info_hashes: vec![info_hash],
+                    cookie: Some(cookie.clone()),
+                };
+                // the network may be down; we'll try again next time
+                let _ = socket.send_to(&msg.to_bytes(*group), group).await;
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(ANNOUNCE_CHECK) => {}
+            _ = registered.notified() => {}
+        }
+    }
+}
+
+/// Hands the peers that announce our torrents on `socket` to whoever registered them.
+async fn receive(
+    socket: Arc<UdpSocket>,
+    cookie: String,
+    torrents: Arc<Mutex<HashMap<[u8; 20], Registered>>>,
+) {
+    let mut buf = [0; 2048];
+    loop {
+        let Ok((n, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(announce) = Announce::parse(&buf[..n]) else {
+            continue;
+        };
+        if announce.cookie.as_deref() == Some(&cookie) {
+            // our own announce, looped back
+            continue;
+        }
+        let peer = SocketAddr::new(from.ip(), announce.port);
+        let torrents = torrents.lock().expect("not poisoned");
+        for info_hash in &announce.info_hashes {
+            if let Some(registered) = torrents.get(info_hash) {
+                // if the torrent has peers queued up already, it can do without this one
+                let _ = registered.peers.try_send(peer);
+            }
+        }
+    }
+}
+
+/// A `BT-SEARCH` announce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    /// The port the announcing peer accepts connections on.
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Set by the announcing peer to recognize its own announces.
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    /// Encodes the announce for sending to `group`.
+    pub fn to_bytes(&self, group: SocketAddr) -> Vec<u8> {
+        let mut msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {group}\r\nPort: {}\r\n",
+            self.port
+        );
+        for info_hash in &self.info_hashes {
+            msg.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            msg.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        msg.push_str("\r\n\r\n");
+        msg.into_bytes()
+    }
+
+    /// Decodes an announce, or returns `None` if `datagram` isn't one.
+    pub fn parse(datagram: &[u8]) -> Option<Self> {
+        let msg = std::str::from_utf8(datagram).ok()?;
+        let mut lines = msg.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    let mut info_hash = [0; 20];
+                    if hex::decode_to_slice(value, &mut info_hash).is_ok() {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(Self {
+            port: port.filter(|&port| port != 0)?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+#[test]
+fn announce_roundtrip() {
+    let announce = Announce {
+        port: 6881,
+        info_hashes: vec![[0xab; 20], [1; 20]],
+        cookie: Some(String::from("c00k1e")),
+    };
+    let bytes = announce.to_bytes(SocketAddr::V4(LSD_GROUP));
+    assert!(bytes.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+    assert_eq!(Announce::parse(&bytes), Some(announce));
+
+    let v6 = String::from_utf8(
+        Announce::parse(&bytes)
+            .unwrap()
+            .to_bytes(SocketAddr::V6(LSD_GROUP6)),
+    )
+    .unwrap();
+    assert!(v6.contains("Host: [ff15::efc0:988f]:6771\r\n"), "{v6}");
+
+    // header names are case-insensitive, and a cookie is optional
+    let other = b"BT-SEARCH * HTTP/1.1\r\nPORT: 51413\r\ninfohash: ABABABABABABABABABABABABABABABABABABABAB\r\n\r\n\r\n";
+    let parsed = Announce::parse(other).unwrap();
+    assert_eq!(parsed.port, 51413);
+    assert_eq!(parsed.info_hashes, vec![[0xab; 20]]);
+    assert_eq!(parsed.cookie, None);
+    assert_eq!(
+        Announce::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+        None
+    );
+}
+
+#[tokio::test]
+async fn lsd_finds_peers_on_the_local_network() {
+    // a group port of our own, so that we don't hear real LSD traffic
+    let port = std::net::UdpSocket::bind("0.0.0.0:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = LsdConfig {
+        group: SocketAddrV4::new(*LSD_GROUP.ip(), port),
+        group6: None,
+    };
+    let us = Lsd::bind(config.clone()).await.unwrap();
+    // the group port is ours now, so this one can only announce
+    let them = Lsd::bind(config).await.unwrap();
+    assert_ne!(us.cookie(), them.cookie());
+
+    let info_hash = [7; 20];
+    let mut found = us.register(info_hash, 1111);
+    let _theirs = them.register(info_hash, 2222);
+    let _unrelated = them.register([8; 20], 3333);
+    let peer = tokio::time::timeout(Duration::from_secs(5), found.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(peer.port(), 2222);
+
+    // nothing else comes: not our own announce, nor the other torrent
+    let more = tokio::time::timeout(Duration::from_millis(200), found.recv()).await;
+    assert!(more.is_err(), "{more:?}");
+}
@@ -0,0 +1,414 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    dht::Dht,
+    download,
+    extension::{self, ExtendedHandshake, MetadataMessage, METADATA_PIECE_SIZE, UT_METADATA_ID},
+    listener::DEFAULT_PORT,
+    peer::{Handshake, MessageFramer, MessageTag},
+    torrent::{Info, Torrent},
+    tracker::{Progress, TrackerTiers},
+};
+
+/// Refuse metadata larger than this; real info dictionaries are far smaller.
+const METADATA_MAX: usize = 1 << 24;
+
+/// How long a peer may keep us waiting for anything while we fetch metadata from it, before we
+/// try another.
+const PEER_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// A parsed magnet link (BEP 9).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+
+    /// The display name (`dn`), if any.
+    pub name: Option<String>,
+
+    /// Tracker URLs (`tr`), in the order they appeared.
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    /// Parses `magnet:?xt=urn:btih:<info-hash>&dn=<name>&tr=<tracker-url>`, where the info hash
+    /// is either 40 hex digits or 32 base32 characters.
+    pub fn parse(link: &str) -> anyhow::Result<Self> {
+        let query = link
+            .strip_prefix("magnet:?")
+            .context("magnet links start with magnet:?")?;
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).context("parse magnet link parameters")?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in params {
+            match key.as_str() {
+                "xt" => {
+                    let Some(hash) = value.strip_prefix("urn:btih:") else {
+                        // some other kind of exact topic we don't understand
+                        continue;
+                    };
+                    info_hash = Some(parse_info_hash(hash)?);
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.context("magnet link has no urn:btih exact topic")?,
+            name,
+            trackers,
+        })
+    }
+
+    /// Finds peers through the magnet's trackers and, if given, the DHT, and fetches the info
+    /// dictionary from the first peer that can provide it, producing a complete `Torrent`.
+    pub async fn fetch_torrent(&self, dht: Option<&Dht>) -> anyhow::Result<Torrent> {
+        anyhow::ensure!(
+            !self.trackers.is_empty() || dht.is_some(),
+            "magnet link has no trackers"
+        );
+
+        let mut trackers =
+            TrackerTiers::new(self.trackers.iter().map(|tr| vec![tr.clone()]).collect());
+        // we don't know how much there is to download until we have the metadata; claim
+        // something so the trackers treat us as a leecher
+        let progress = Progress {
+            left: 1,
+            ..Progress::default()
+        };
+        let peers =
+            download::find_peers(&mut trackers, dht, self.info_hash, progress, DEFAULT_PORT)
+                .await?;
+
+        let info_hash = self.info_hash;
+        let mut attempts = futures_util::stream::iter(peers)
+            .map(|peer_addr| async move {
+                let metadata = fetch_metadata(peer_addr, info_hash).await;
+                (peer_addr, metadata)
+            })
+            .buffer_unordered(5);
+        let mut t = None;
+        while let Some((peer_addr, metadata)) = attempts.next().await {
+            match metadata.and_then(|info| self.torrent_with(info)) {
+                Ok(fetched) => {
+                    t = Some(fetched);
+                    break;
+                }
+                Err(e) => eprintln!("failed to fetch metadata from {peer_addr}: {e:#}"),
+            }
+        }
+        t.context("no peer provided metadata")
+    }
+
+    /// The torrent for the magnet's trackers and `info`, the raw info dictionary that has
+    /// already been checked against the info hash.
+    fn torrent_with(&self, info: Vec<u8>) -> anyhow::Result<Torrent> {
+        let parsed: Info = serde_bencode::from_bytes(&info).context("parse info dictionary")?;
+        Ok(Torrent {
+            announce: self.trackers.first().cloned().unwrap_or_default(),
+            announce_list: (self.trackers.len() > 1)
+                .then(|| self.trackers.iter().map(|tr| vec![tr.clone()]).collect()),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            nodes: None,
+            info: parsed,
+            // it may have keys `Info` drops, and is what the info hash is the hash of
+            raw_info: Some(info),
+        })
+    }
+}
+
+/// Fetches and verifies the raw (bencoded) info dictionary for `info_hash` from one peer using
+/// the `ut_metadata` extension, giving up if the peer goes quiet for [`PEER_TIMEOUT`].
+pub async fn fetch_metadata(peer_addr: SocketAddr, info_hash: [u8; 20]) -> anyhow::Result<Vec<u8>> {
+    fetch_metadata_within(peer_addr, info_hash, PEER_TIMEOUT).await
+}
+
+/// `future`, or an error if it takes longer than `timeout`.
+async fn within<T>(
+    timeout: Duration,
+    future: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::time::timeout(timeout, future)
+        .await
+        .context("peer timed out")?
+}
+
+async fn fetch_metadata_within(
+    peer_addr: SocketAddr,
+    info_hash: [u8; 20],
+    timeout: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    let mut peer = within(timeout, async {
+        tokio::net::TcpStream::connect(peer_addr)
+            .await
+            .context("connect to peer")
+    })
+    .await?;
+    let mut handshake = Handshake::new(info_hash, *b"00112233445566778899").with_extensions();
+    {
+        let handshake_bytes = handshake.as_bytes_mut();
+        peer.write_all(handshake_bytes)
+            .await
+            .context("write handshake")?;
+
+        within(timeout, async {
+            peer.read_exact(handshake_bytes)
+                .await
+                .context("read handshake")
+        })
+        .await?;
+    }
+    anyhow::ensure!(handshake.length == 19);
+    anyhow::ensure!(handshake.bittorent_protocol == *b"BitTorrent protocol");
+    anyhow::ensure!(
+        handshake.info_hash == info_hash,
+        "peer serves another torrent"
+    );
+    anyhow::ensure!(
+        handshake.supports_extensions(),
+        "peer does not support the extension protocol"
+    );
+
+    let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
+    let ours = ExtendedHandshake {
+        m: [(String::from("ut_metadata"), UT_METADATA_ID)].into(),
+        ..ExtendedHandshake::default()
+    };
+    peer.send(extension::message(extension::HANDSHAKE_ID, &ours)?)
+        .await
+        .context("send extended handshake")?;
+
+    let theirs = loop {
+        let msg = within(timeout, async {
+            peer.next()
+                .await
+                .context("peer closed connection before extended handshake")?
+                .context("peer message was invalid")
+        })
+        .await?;
+        if msg.tag != MessageTag::Extended {
+            // bitfield, have, unchoke, ...: irrelevant until we know what we're downloading
+            continue;
+        }
+        let (id, handshake, _) = extension::parse::<ExtendedHandshake>(&msg.payload)?;
+        if id == extension::HANDSHAKE_ID {
+            break handshake;
+        }
+    };
+    let their_id = theirs
+        .id_of("ut_metadata")
+        .context("peer does not support ut_metadata")?;
+    let size = theirs
+        .metadata_size
+        .context("peer did not announce the metadata size")?;
+    anyhow::ensure!(
+        size > 0 && size <= METADATA_MAX,
+        "implausible metadata size {size}"
+    );
+
+    let npieces = size.div_ceil(METADATA_PIECE_SIZE);
+    let mut metadata = Vec::with_capacity(size);
+    for piece in 0..npieces {
+        peer.send(extension::message(
+            their_id,
+            &MetadataMessage::request(piece),
+        )?)
+        .await
+        .with_context(|| format!("request metadata piece {piece}"))?;
+
+        loop {
+            let msg = within(timeout, async {
+                peer.next()
+                    .await
+                    .context("peer closed connection during metadata exchange")?
+                    .context("peer message was invalid")
+            })
+            .await?;
+            if msg.tag != MessageTag::Extended {
+                continue;
+            }
+            let (id, reply, data) = extension::parse::<MetadataMessage>(&msg.payload)?;
+            if id != UT_METADATA_ID {
+                continue;
+            }
+            anyhow::ensure!(
+                reply.piece == piece,
+                "peer sent metadata piece out of order"
+            );
+            anyhow::ensure!(
+                reply.msg_type == MetadataMessage::DATA,
+                "peer rejected request for metadata piece {piece}"
+            );
+            let expected = METADATA_PIECE_SIZE.min(size - piece * METADATA_PIECE_SIZE);
+            anyhow::ensure!(
+                data.len() == expected,
+                "metadata piece {piece} has the wrong length"
+            );
+            metadata.extend_from_slice(data);
+            break;
+        }
+    }
+
+    let mut hasher = Sha1::new();
+    hasher.update(&metadata);
+    let hash: [u8; 20] = hasher.finalize().into();
+    anyhow::ensure!(hash == info_hash, "metadata does not match the info hash");
+    Ok(metadata)
+}
+
+fn parse_info_hash(hash: &str) -> anyhow::Result<[u8; 20]> {
+    match hash.len() {
+        40 => {
+            let mut info_hash = [0; 20];
+            hex::decode_to_slice(hash, &mut info_hash).context("info hash is not valid hex")?;
+            Ok(info_hash)
+        }
+        32 => base32_decode(hash).context("info hash is not valid base32"),
+        n => anyhow::bail!("info hash has unexpected length {n}"),
+    }
+}
+
+/// Decodes an unpadded RFC 4648 base32 string of 32 characters into 20 bytes.
+fn base32_decode(s: &str) -> Option<[u8; 20]> {
+    let mut out = [0; 20];
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    let mut i = 0;
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | u64::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            *out.get_mut(i)? = (buffer >> bits) as u8;
+            i += 1;
+        }
+    }
+    (i == out.len()).then_some(out)
+}
+
+#[test]
+fn parse_hex_magnet() {
+    let m = Magnet::parse(
+        "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce",
+    )
+    .unwrap();
+    assert_eq!(
+        hex::encode(m.info_hash),
+        "ad42ce8109f54c99613ce38f9b4d87e70f24a165"
+    );
+    assert_eq!(m.name.as_deref(), Some("magnet1.gif"));
+    assert_eq!(
+        m.trackers,
+        vec!["http://bittorrent-test-tracker.codecrafters.io/announce"]
+    );
+}
+
+#[test]
+fn parse_base32_magnet() {
+    let m = Magnet::parse("magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF").unwrap();
+    assert_eq!(
+        hex::encode(m.info_hash),
+        "ad42ce8109f54c99613ce38f9b4d87e70f24a165"
+    );
+    assert!(m.trackers.is_empty());
+    assert!(Magnet::parse("magnet:?dn=nothing").is_err());
+}
+
+#[tokio::test]
+async fn fetch_metadata_from_peer() {
+    let info = b"d6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+    let info_hash: [u8; 20] = Sha1::digest(info).into();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seeder = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = Handshake::new([0; 20], [0; 20]);
+        stream.read_exact(handshake.as_bytes_mut()).await.unwrap();
+        assert!(handshake.supports_extensions());
+        let mut reply =
+            Handshake::new(handshake.info_hash, *b"-xx0000-000000000000").with_extensions();
+        stream.write_all(reply.as_bytes_mut()).await.unwrap();
+
+        let mut stream = tokio_util::codec::Framed::new(stream, MessageFramer);
+        let theirs = ExtendedHandshake {
+            m: [(String::from("ut_metadata"), 3)].into(),
+            metadata_size: Some(info.len()),
+            ..ExtendedHandshake::default()
+        };
+        stream
+            .send(extension::message(extension::HANDSHAKE_ID, &theirs).unwrap())
+            .await
+            .unwrap();
+        let msg = stream.next().await.unwrap().unwrap();
+        let (id, ours, _) = extension::parse::<ExtendedHandshake>(&msg.payload).unwrap();
+        assert_eq!(id, extension::HANDSHAKE_ID);
+        assert_eq!(ours.id_of("ut_metadata"), Some(UT_METADATA_ID));
+
+        let msg = stream.next().await.unwrap().unwrap();
+        let (id, request, _) = extension::parse::<MetadataMessage>(&msg.payload).unwrap();
+        assert_eq!(id, 3);
+        assert_eq!(request.msg_type, MetadataMessage::REQUEST);
+        assert_eq!(request.piece, 0);
+        let mut data = extension::message(
+            UT_METADATA_ID,
+            &MetadataMessage {
+                msg_type: MetadataMessage::DATA,
+                piece: 0,
+                total_size: Some(info.len()),
+            },
+        )
+        .unwrap();
+        data.payload.extend(info);
+        stream.send(data).await.unwrap();
+    });
+
+    let metadata = fetch_metadata(addr, info_hash).await.unwrap();
+    assert_eq!(metadata, info);
+    seeder.await.unwrap();
+}
+
+#[tokio::test]
+async fn fetch_metadata_gives_up_on_silent_peers() {
+    // accepts the connection, but never says anything
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = silent.local_addr().unwrap();
+    let err = fetch_metadata_within(addr, [1; 20], Duration::from_millis(100))
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("timed out"), "{err:#}");
+}
+
+#[test]
+fn fetched_info_keeps_keys_we_do_not_model() {
+    let info =
+        b"d6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:xyze";
+    let magnet = Magnet {
+        info_hash: Sha1::digest(info).into(),
+        name: None,
+        trackers: vec![String::from("http://a")],
+    };
+    let t = magnet.torrent_with(info.to_vec()).unwrap();
+    assert_eq!(t.info_hash(), magnet.info_hash);
+    assert_eq!(t.info_bytes(), info);
+    assert_eq!(t.info.name, "a");
+    assert_eq!(t.announce, "http://a");
+}
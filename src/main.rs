@@ -1,11 +1,18 @@
-use std::{net::SocketAddrV4, path::PathBuf};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 
 use anyhow::Context;
 use bittorrent_starter_rust::{
-    download::download_all,
+    create::{create, CreateOptions},
+    dht::{Dht, DhtConfig},
+    listener::{Listener, DEFAULT_PORT},
+    lsd::{Lsd, LsdConfig},
+    magnet::Magnet,
     peer::{Handshake, Message, MessageFramer, MessageTag, Piece, Request},
+    swarm::SwarmConfig,
     torrent::{self, decode_bencode_value, Torrent},
-    tracker::{urlencode, TrackerRequest, TrackerResponse},
+    tracker::{Progress, TrackerTiers},
+    verify::{verify, PieceStatus},
     BLOCK_MAX,
 };
 use clap::{Parser, Subcommand};
@@ -28,6 +35,13 @@ enum Commands {
     Info {
         torrent: PathBuf,
     },
+    #[clap(name = "magnet_info")]
+    MagnetInfo {
+        /// Only use the magnet's trackers to find peers.
+        #[arg(long)]
+        no_dht: bool,
+        link: String,
+    },
     Peers {
         torrent: PathBuf,
     },
@@ -45,7 +59,52 @@ enum Commands {
     Download {
         #[arg(short)]
         output: PathBuf,
+        /// Only use trackers to find peers.
+        #[arg(long)]
+        no_dht: bool,
+        /// Don't look for peers on the local network.
+        #[arg(long)]
+        no_lsd: bool,
+        /// How many peers to upload to at once.
+        #[arg(long, default_value_t = SwarmConfig::default().upload_slots)]
+        upload_slots: usize,
+        /// The most requests to keep outstanding with one peer.
+        #[arg(long, default_value_t = SwarmConfig::default().max_pipeline_depth)]
+        max_pipeline_depth: usize,
+        /// A .torrent file or a magnet link.
+        torrent: String,
+    },
+    /// Seed an existing torrent+data pair until interrupted.
+    Seed {
+        /// Only use trackers to find peers.
+        #[arg(long)]
+        no_dht: bool,
+        /// Don't look for peers on the local network.
+        #[arg(long)]
+        no_lsd: bool,
+        /// How many peers to upload to at once.
+        #[arg(long, default_value_t = SwarmConfig::default().upload_slots)]
+        upload_slots: usize,
+        torrent: PathBuf,
+        data: PathBuf,
+    },
+    Verify {
         torrent: PathBuf,
+        data: PathBuf,
+    },
+    Create {
+        #[arg(short)]
+        output: PathBuf,
+        /// Tracker URL; repeat for more tiers, comma-separate URLs within a tier.
+        #[arg(short, long = "announce", required = true)]
+        announce: Vec<String>,
+        #[arg(long)]
+        piece_length: Option<usize>,
+        #[arg(long)]
+        private: bool,
+        #[arg(long)]
+        comment: Option<String>,
+        path: PathBuf,
     },
 }
 
@@ -62,59 +121,31 @@ async fn main() -> anyhow::Result<()> {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
             let t: Torrent =
                 serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
-            println!("Tracker URL: {}", t.announce);
-
-            let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!()
-            };
-            println!("Length: {length}");
-
-            let info_hash = t.info_hash();
-            println!("Info Hash: {}", hex::encode(info_hash));
-
-            // Piece length and piece Hashes
-            println!("Piece Length: {}", t.info.plength);
-            println!("Piece Haashes:");
-            for piece in t.info.pieces.0 {
-                println!("{}", hex::encode(piece));
-            }
+            print_info(&t);
+        }
+        Commands::MagnetInfo { no_dht, link } => {
+            let magnet = Magnet::parse(&link).context("parse magnet link")?;
+            let dht = if no_dht { None } else { start_dht().await };
+            let t = magnet
+                .fetch_torrent(dht.as_ref())
+                .await
+                .context("fetch metadata")?;
+            print_info(&t);
+            save_dht(dht).await;
         }
         Commands::Peers { torrent } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
             let t: Torrent =
                 serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
 
-            let length = if let torrent::Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!()
-            };
-
             let info_hash = t.info_hash();
 
-            let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                compact: 1,
+            let mut trackers = TrackerTiers::new(t.trackers());
+            let progress = Progress {
+                left: t.length(),
+                ..Progress::default()
             };
-            let url_params =
-                serde_urlencoded::to_string(request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-
-            let response = reqwest::get(tracker_url).await.context("query tracker")?;
-            let response = response.bytes().await.context("fetch tracker response")?;
-            let response: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
+            let response = trackers.announce(info_hash, progress, 6881).await?;
 
             for peer in response.peers.0 {
                 println!("{}:{}", peer.ip(), peer.port());
@@ -127,7 +158,7 @@ async fn main() -> anyhow::Result<()> {
 
             let info_hash = t.info_hash();
 
-            let peer = peer.parse::<SocketAddrV4>().context("parse peer address")?;
+            let peer = peer.parse::<SocketAddr>().context("parse peer address")?;
             let mut peer = tokio::net::TcpStream::connect(peer)
                 .await
                 .context("connect to peer")?;
@@ -161,36 +192,17 @@ async fn main() -> anyhow::Result<()> {
             let t: Torrent =
                 serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
 
-            let file_length = if let torrent::Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!()
-            };
+            let file_length = t.length();
             assert!(piece_i < t.info.pieces.0.len());
 
             let info_hash = t.info_hash();
 
-            let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
+            let mut trackers = TrackerTiers::new(t.trackers());
+            let progress = Progress {
                 left: file_length,
-                compact: 1,
+                ..Progress::default()
             };
-            let url_params =
-                serde_urlencoded::to_string(request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-
-            let response = reqwest::get(tracker_url).await.context("query tracker")?;
-            let response = response.bytes().await.context("fetch tracker response")?;
-            let tracker_info: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
+            let tracker_info = trackers.announce(info_hash, progress, 6881).await?;
 
             let peer = tracker_info.peers.0[0];
             let mut peer = tokio::net::TcpStream::connect(peer)
@@ -246,8 +258,7 @@ async fn main() -> anyhow::Result<()> {
             } else {
                 t.info.plength
             };
-            // the + (BLOCK_MAX - 1) rounds up
-            let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
+            let nblocks = piece_size.div_ceil(BLOCK_MAX);
             let mut all_blocks = Vec::with_capacity(piece_size);
             for block in 0..nblocks {
                 let block_size = if block == nblocks - 1 {
@@ -293,7 +304,7 @@ async fn main() -> anyhow::Result<()> {
 
             let mut hasher = Sha1::new();
             hasher.update(&all_blocks);
-            let hash: [u8; 20] = hasher.finalize().try_into().expect("");
+            let hash: [u8; 20] = hasher.finalize().into();
             assert_eq!(hash, piece_hash);
 
             tokio::fs::write(&output, all_blocks)
@@ -301,18 +312,180 @@ async fn main() -> anyhow::Result<()> {
                 .context("write out downloaded piece")?;
             println!("Piece {piece_i} downloaded  to {}.", output.display());
         }
-        Commands::Download { output, torrent } => {
-            let torrent: Torrent = Torrent::read(torrent).await?;
+        Commands::Download {
+            output,
+            no_dht,
+            no_lsd,
+            upload_slots,
+            max_pipeline_depth,
+            torrent,
+        } => {
+            let dht = if no_dht { None } else { start_dht().await };
+            let torrent: Torrent = if torrent.starts_with("magnet:") {
+                let magnet = Magnet::parse(&torrent).context("parse magnet link")?;
+                magnet
+                    .fetch_torrent(dht.as_ref())
+                    .await
+                    .context("fetch metadata")?
+            } else {
+                Torrent::read(torrent).await?
+            };
             torrent.print_tree();
-            // torrent.download_all_to_file(output).await?;
-            let files = download_all(&torrent).await?;
-            tokio::fs::write(
-                output,
-                files.into_iter().next().expect("always one file").bytes(),
-            )
-            .await?;
+            let listener = start_listener().await;
+            let lsd = if no_lsd { None } else { start_lsd().await };
+            torrent
+                .download_all_to_file(
+                    output,
+                    dht.as_ref(),
+                    listener.as_ref(),
+                    lsd.as_ref(),
+                    SwarmConfig {
+                        upload_slots,
+                        max_pipeline_depth,
+                        ..SwarmConfig::default()
+                    },
+                )
+                .await?;
+            save_dht(dht).await;
+        }
+        Commands::Seed {
+            no_dht,
+            no_lsd,
+            upload_slots,
+            torrent,
+            data,
+        } => {
+            let torrent: Torrent = Torrent::read(torrent).await?;
+            let dht = if no_dht { None } else { start_dht().await };
+            let listener = start_listener().await;
+            let lsd = if no_lsd { None } else { start_lsd().await };
+            torrent
+                .seed_from(
+                    data,
+                    dht.as_ref(),
+                    listener.as_ref(),
+                    lsd.as_ref(),
+                    SwarmConfig {
+                        upload_slots,
+                        ..SwarmConfig::default()
+                    },
+                )
+                .await?;
+        }
+        Commands::Verify { torrent, data } => {
+            let torrent: Torrent = Torrent::read(torrent).await?;
+            let v = verify(&torrent, &data).await.context("verify data")?;
+
+            let npieces = v.pieces.len();
+            let complete = v.with_status(PieceStatus::Complete).count();
+            let corrupt = v.with_status(PieceStatus::Corrupt).collect::<Vec<_>>();
+            let missing = v.with_status(PieceStatus::Missing).collect::<Vec<_>>();
+            println!("Complete: {complete}/{npieces}");
+            println!("Corrupt: {} {corrupt:?}", corrupt.len());
+            println!("Missing: {} {missing:?}", missing.len());
+            println!("Bitfield: {}", hex::encode(v.bitfield().as_bytes()));
+        }
+        Commands::Create {
+            output,
+            announce,
+            piece_length,
+            private,
+            comment,
+            path,
+        } => {
+            let opts = CreateOptions {
+                trackers: announce
+                    .iter()
+                    .map(|tier| tier.split(',').map(String::from).collect())
+                    .collect(),
+                piece_length,
+                private,
+                comment,
+                created_by: Some(format!(
+                    "{}/{}",
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION")
+                )),
+                creation_date: None,
+            };
+            let t = create(&path, opts).await.context("create torrent")?;
+            t.write(&output).await?;
+            println!("Info Hash: {}", hex::encode(t.info_hash()));
         }
     }
 
     Ok(())
 }
+
+/// Joins the DHT, carrying on without it if that fails.
+async fn start_dht() -> Option<Dht> {
+    let dht = match Dht::bind(DhtConfig::default()).await {
+        Ok(dht) => dht,
+        Err(e) => {
+            eprintln!("DHT unavailable: {e:#}");
+            return None;
+        }
+    };
+    if let Err(e) = dht.bootstrap().await {
+        eprintln!("failed to bootstrap DHT: {e:#}");
+    }
+    Some(dht)
+}
+
+/// Listens for incoming peers over IPv6 and IPv4, or over IPv4 alone if there is no IPv6,
+/// carrying on without them if that fails.
+async fn start_listener() -> Option<Listener> {
+    let dual_stack = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), DEFAULT_PORT);
+    let v4 = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), DEFAULT_PORT);
+    let listener = match Listener::bind(dual_stack).await {
+        Ok(listener) => Ok(listener),
+        Err(_) => Listener::bind(v4).await,
+    };
+    match listener {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            eprintln!("not accepting incoming peers: {e:#}");
+            None
+        }
+    }
+}
+
+/// Starts local service discovery, carrying on without it if that fails.
+async fn start_lsd() -> Option<Lsd> {
+    match Lsd::bind(LsdConfig::default()).await {
+        Ok(lsd) => Some(lsd),
+        Err(e) => {
+            eprintln!("not looking for peers on the local network: {e:#}");
+            None
+        }
+    }
+}
+
+async fn save_dht(dht: Option<Dht>) {
+    if let Some(dht) = dht {
+        if let Err(e) = dht.save().await {
+            eprintln!("failed to save DHT state: {e:#}");
+        }
+    }
+}
+
+fn print_info(t: &Torrent) {
+    println!("Tracker URL: {}", t.announce);
+    println!("Length: {}", t.length());
+    if let torrent::Keys::MultiFile { files } = &t.info.keys {
+        println!("Files:");
+        for file in files {
+            println!("{}/{} ({})", t.info.name, file.path.join("/"), file.length);
+        }
+    }
+
+    let info_hash = t.info_hash();
+    println!("Info Hash: {}", hex::encode(info_hash));
+
+    // Piece length and piece Hashes
+    println!("Piece Length: {}", t.info.plength);
+    println!("Piece Haashes:");
+    for piece in &t.info.pieces.0 {
+        println!("{}", hex::encode(piece));
+    }
+}
@@ -1,4 +1,6 @@
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
 use bytes::BufMut;
@@ -6,25 +8,53 @@ use bytes::{Buf, BytesMut};
 use futures_util::{SinkExt, StreamExt};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::broadcast;
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
-use crate::BLOCK_MAX;
+use crate::extension::{self, ExtendedHandshake};
+use crate::peer::pipeline::Pipeline;
+use crate::pex::{self, PexSender};
+use crate::scheduler::{Block, Scheduler};
+use crate::swarm::{PeerStats, Swarm};
+
+pub mod pipeline;
+
+/// The largest block we serve in one Piece message; bigger requests are ignored. Nobody asks for
+/// more than we do, and anything much bigger wouldn't fit in a frame.
+const MAX_REQUEST: usize = crate::BLOCK_MAX;
+
+/// How long to wait for the peer's bitfield after the handshake. Peers with no pieces may not send
+/// one at all.
+const BITFIELD_TIMEOUT: Duration = Duration::from_secs(5);
 
-// TODO: ideally, Peer should keep track of what pieces we have downloaded (and references to them)
-// so that we can respond to Requests from the other side, also, choking/unchoking the other side.
 pub(crate) struct Peer {
-    pub(crate) addr: SocketAddrV4,
+    pub(crate) addr: SocketAddr,
     pub(crate) stream: Framed<TcpStream, MessageFramer>,
     pub(crate) bitfield: Bitfield,
+    /// Whether the peer is choking us.
     pub(crate) choked: bool,
+    /// Whether we are choking the peer.
+    pub(crate) am_choking: bool,
+    /// Whether we told the peer we want pieces from it.
+    pub(crate) am_interested: bool,
+    pub(crate) peer_interested: bool,
+    /// The other side's extended handshake, once it has sent one.
+    pub(crate) extensions: Option<ExtendedHandshake>,
+    /// The blocks we have asked the peer for.
+    pub(crate) pipeline: Pipeline,
+    /// What we have told the peer about the other peers in the swarm.
+    pex: PexSender,
+    pub(crate) swarm: Arc<Swarm>,
 }
 
 impl Peer {
-    pub async fn new(peer_addr: SocketAddrV4, info_hash: [u8; 20]) -> anyhow::Result<Self> {
+    /// Connects to the peer at `peer_addr` and handshakes with it.
+    pub async fn new(peer_addr: SocketAddr, swarm: Arc<Swarm>) -> anyhow::Result<Self> {
         let mut peer = tokio::net::TcpStream::connect(peer_addr)
             .await
             .context("connect to peer")?;
-        let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
+        let mut handshake =
+            Handshake::new(swarm.info_hash, *b"00112233445566778899").with_extensions();
         {
             let handshake_bytes = handshake.as_bytes_mut();
             peer.write_all(handshake_bytes)
@@ -38,204 +68,554 @@ impl Peer {
 
         anyhow::ensure!(handshake.length == 19);
         anyhow::ensure!(handshake.bittorent_protocol == *b"BitTorrent protocol");
+        anyhow::ensure!(
+            handshake.info_hash == swarm.info_hash,
+            "peer is in a different swarm"
+        );
+        let peer = Self::start(peer, peer_addr, handshake.supports_extensions(), swarm).await?;
+        peer.swarm.update_peer(peer_addr, |stats| {
+            stats.listen_addr = Some(peer_addr);
+            stats.outgoing = true;
+        });
+        Ok(peer)
+    }
 
-        let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-        let bitfield = peer
-            .next()
+    /// Completes the handshake of a peer that connected to us, once its handshake (`theirs`) has
+    /// been read and matched to `swarm`.
+    pub async fn accept(
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+        theirs: &Handshake,
+        swarm: Arc<Swarm>,
+    ) -> anyhow::Result<Self> {
+        let mut handshake =
+            Handshake::new(swarm.info_hash, *b"00112233445566778899").with_extensions();
+        stream
+            .write_all(handshake.as_bytes_mut())
             .await
-            .expect("peer always sends a bitfields")
-            .context("peer message was invalid")?;
-        anyhow::ensure!(bitfield.tag == MessageTag::Bitfield);
+            .context("write handshake")?;
+        Self::start(stream, peer_addr, theirs.supports_extensions(), swarm).await
+    }
 
-        Ok(Self {
+    /// Runs the part of connection setup that follows the handshake, whichever side initiated
+    /// it: exchanges extended handshakes and bitfields.
+    async fn start(
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+        extended: bool,
+        swarm: Arc<Swarm>,
+    ) -> anyhow::Result<Self> {
+        let mut peer = Self {
             addr: peer_addr,
-            stream: peer,
-            bitfield: Bitfield::from_payload(bitfield.payload),
+            stream: tokio_util::codec::Framed::new(stream, MessageFramer),
+            bitfield: Bitfield::new(swarm.npieces()),
             choked: true,
-        })
+            am_choking: true,
+            am_interested: false,
+            peer_interested: false,
+            extensions: None,
+            pipeline: Pipeline::new(swarm.config.pipeline_depth, swarm.config.max_pipeline_depth),
+            pex: PexSender::default(),
+            swarm,
+        };
+        peer.swarm
+            .peers
+            .lock()
+            .expect("not poisoned")
+            .insert(peer_addr, PeerStats::default());
+
+        if extended {
+            let ours = peer.swarm.registry.handshake(peer_addr.ip());
+            peer.stream
+                .send(extension::message(extension::HANDSHAKE_ID, &ours)?)
+                .await
+                .context("send extended handshake")?;
+        }
+        let ours = peer.swarm.bitfield();
+        if ours.pieces().next().is_some() {
+            peer.stream
+                .send(Message {
+                    tag: MessageTag::Bitfield,
+                    payload: ours.as_bytes().to_vec(),
+                })
+                .await
+                .context("send bitfield")?;
+        }
+
+        loop {
+            let Ok(msg) = tokio::time::timeout(BITFIELD_TIMEOUT, peer.next_message()).await else {
+                // a peer without pieces may skip its bitfield and say nothing until it wants data
+                break;
+            };
+            let msg = msg?;
+            match msg.tag {
+                MessageTag::Bitfield => {
+                    peer.bitfield = Bitfield::from_payload(msg.payload, peer.swarm.npieces())
+                        .context("peer sent an invalid bitfield")?;
+                    break;
+                }
+                // some peers send their extended handshake before the bitfield
+                MessageTag::Extended if extended => peer.handle_extended(&msg.payload).await?,
+                _ => {
+                    // no bitfield, so the peer has no pieces (or only announces them with Have)
+                    peer.handle_message(msg).await?;
+                    break;
+                }
+            }
+        }
+        peer.update_seed();
+        peer.update_interest().await?;
+
+        Ok(peer)
     }
 
-    pub async fn download(
-        &mut self,
-        piece_i: usize,
-        block_i: usize,
-        block_size: u32,
-    ) -> anyhow::Result<Vec<u8>> {
-        anyhow::ensure!(self.bitfield.has_piece(piece_i));
-
-        let mut request = Request::new(piece_i as u32, (block_i * BLOCK_MAX) as u32, block_size);
-        let request_bytes = Vec::from(request.as_bytes_mut());
+    /// The next message from the peer.
+    pub(crate) async fn next_message(&mut self) -> anyhow::Result<Message> {
         self.stream
-            .send(Message {
-                tag: MessageTag::Request,
-                payload: request_bytes,
-            })
-            .await
-            .with_context(|| format!("send request for {block_i}"))?;
-
-        let piece = self
-            .stream
             .next()
             .await
-            .expect("peer always sends a request")
-            .context("peer request message was invalid")?;
-        anyhow::ensure!(piece.tag == MessageTag::Piece);
-        anyhow::ensure!(!piece.payload.is_empty());
+            .context("peer closed connection")?
+            .context("peer message was invalid")
+    }
 
-        let piece = Piece::ref_from_bytes(&piece.payload[..])
-            .expect("always get all Piece response fields from peer");
-        anyhow::ensure!(piece.index() as usize == piece_i);
-        anyhow::ensure!(piece.begin() as usize == block_i * BLOCK_MAX);
-        anyhow::ensure!(piece.block().len() == block_size as usize);
+    /// Updates our view of the peer for a message it sent, and answers it if it asks for
+    /// something: its requests are served from storage, extended messages go to our extensions.
+    ///
+    /// Piece messages are handed back, since only the download side knows what to do with them.
+    pub(crate) async fn handle_message(&mut self, msg: Message) -> anyhow::Result<Option<Message>> {
+        match msg.tag {
+            MessageTag::Choke => self.choked = true,
+            MessageTag::Unchoke => self.choked = false,
+            MessageTag::Interested => {
+                self.peer_interested = true;
+                self.swarm
+                    .update_peer(self.addr, |stats| stats.interested = true);
+                // don't make the peer wait for the next rechoke if there is a slot free
+                self.swarm.unchoke_if_free(self.addr);
+            }
+            MessageTag::NotInterested => {
+                self.peer_interested = false;
+                self.swarm
+                    .update_peer(self.addr, |stats| stats.interested = false);
+            }
+            MessageTag::Have => {
+                let piece_i = u32::from_be_bytes(
+                    msg.payload[..]
+                        .try_into()
+                        .context("have message must hold a piece index")?,
+                ) as usize;
+                anyhow::ensure!(
+                    piece_i < self.swarm.npieces(),
+                    "peer has piece {piece_i}, but there are only {}",
+                    self.swarm.npieces()
+                );
+                self.bitfield.set_piece(piece_i);
+                self.update_seed();
+                self.update_interest().await?;
+            }
+            MessageTag::Bitfield => {
+                anyhow::bail!("peer sent bitfield after handshake has been completed");
+            }
+            MessageTag::Request => {
+                let request = Request::ref_from_bytes(&msg.payload)
+                    .context("request message must hold index, begin and length")?;
+                self.serve(request.index(), request.begin(), request.length())
+                    .await?;
+            }
+            MessageTag::Cancel => {
+                // requests are answered as soon as they arrive, so there is nothing to cancel
+            }
+            MessageTag::Piece => {
+                let block_len = msg.payload.len().saturating_sub(8) as u64;
+                self.swarm.record_download(self.addr, block_len);
+                self.apply_choke().await?;
+                return Ok(Some(msg));
+            }
+            MessageTag::Extended => self.handle_extended(&msg.payload).await?,
+        }
+        self.apply_choke().await?;
+        Ok(None)
+    }
 
-        Ok(Vec::from(piece.block()))
+    /// Chokes or unchokes the peer, if the choker changed its mind about it.
+    pub(crate) async fn apply_choke(&mut self) -> anyhow::Result<()> {
+        let unchoked = self
+            .swarm
+            .update_peer(self.addr, |stats| stats.unchoked)
+            .unwrap_or(false);
+        if unchoked != self.am_choking {
+            return Ok(());
+        }
+        self.am_choking = !unchoked;
+        let tag = if unchoked {
+            MessageTag::Unchoke
+        } else {
+            MessageTag::Choke
+        };
+        self.send(tag, Vec::new()).await
     }
 
-    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
-        self.bitfield.has_piece(piece_i)
+    /// Tells the peer whether we want anything from it.
+    pub(crate) async fn set_interested(&mut self, interested: bool) -> anyhow::Result<()> {
+        if interested == self.am_interested {
+            return Ok(());
+        }
+        self.am_interested = interested;
+        let tag = if interested {
+            MessageTag::Interested
+        } else {
+            MessageTag::NotInterested
+        };
+        self.send(tag, Vec::new()).await
     }
 
-    pub(crate) async fn participate(
-        &mut self,
-        piece_i: usize,
-        piece_size: usize,
-        nblocks: usize,
-        submit: kanal::AsyncSender<usize>,
-        tasks: kanal::AsyncReceiver<usize>,
-        finish: tokio::sync::mpsc::Sender<Message>,
-    ) -> anyhow::Result<()> {
-        self.stream
-            .send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
+    /// Becomes interested in the peer if it has a piece we lack, and loses interest once it
+    /// doesn't.
+    pub(crate) async fn update_interest(&mut self) -> anyhow::Result<()> {
+        let wanted = self
+            .bitfield
+            .pieces()
+            .take_while(|&piece_i| piece_i < self.swarm.npieces())
+            .any(|piece_i| !self.swarm.has_piece(piece_i));
+        self.set_interested(wanted).await
+    }
+
+    /// Records in the swarm whether the peer has every piece.
+    fn update_seed(&self) {
+        let npieces = self.swarm.npieces();
+        let seed = (0..npieces).all(|piece_i| self.has_piece(piece_i));
+        self.swarm.update_peer(self.addr, |stats| stats.seed = seed);
+    }
+
+    /// Tells the peer about the peers that joined or left the swarm since we last did, if it
+    /// supports peer exchange and the torrent allows it.
+    async fn send_pex(&mut self) -> anyhow::Result<()> {
+        let their_id = self
+            .extensions
+            .as_ref()
+            .and_then(|theirs| theirs.id_of(pex::NAME));
+        let (Some(their_id), Some(_)) = (their_id, &self.swarm.pex) else {
+            return Ok(());
+        };
+        let current: Vec<(SocketAddr, u8)> = self
+            .swarm
+            .peers
+            .lock()
+            .expect("not poisoned")
+            .iter()
+            .filter(|&(&addr, _)| addr != self.addr)
+            .filter_map(|(_, stats)| {
+                let mut flags = 0;
+                if stats.seed {
+                    flags |= pex::SEED;
+                }
+                if stats.outgoing {
+                    flags |= pex::REACHABLE;
+                }
+                Some((stats.listen_addr?, flags))
             })
+            .collect();
+        let Some(msg) = self.pex.update(&current) else {
+            return Ok(());
+        };
+        self.stream
+            .send(extension::message(their_id, &msg)?)
             .await
-            .context("send interested message")?;
-
-        // TODO: timeout, error, and return block to submit if .next() timed out
-        'task: loop {
-            while self.choked {
-                let unchoke = self
-                    .stream
-                    .next()
-                    .await
-                    .expect("peer always sends a unchoke")
-                    .context("peer message was invalid")?;
+            .context("send ut_pex message")
+    }
+
+    /// Answers a request for a block of a piece we have; other requests are ignored, as are
+    /// requests made while we are choking the peer.
+    async fn serve(&mut self, index: u32, begin: u32, length: u32) -> anyhow::Result<()> {
+        let (piece_i, begin, length) = (index as usize, begin as usize, length as usize);
+        // a request may have been sent before it saw our choke, which it will have by now
+        if self.am_choking
+            || length > MAX_REQUEST
+            || piece_i >= self.swarm.npieces()
+            || !self.swarm.has_piece(piece_i)
+            || begin + length > self.swarm.storage.piece_length(piece_i)
+        {
+            return Ok(());
+        }
+
+        let block = self
+            .swarm
+            .storage
+            .read_block(piece_i, begin, length)
+            .await
+            .with_context(|| format!("read block of piece {piece_i} to serve"))?;
+        let mut payload = Vec::with_capacity(8 + block.len());
+        payload.extend(index.to_be_bytes());
+        payload.extend((begin as u32).to_be_bytes());
+        payload.extend(block);
+        self.send(MessageTag::Piece, payload).await?;
+        self.swarm.record_upload(self.addr, length as u64);
+        Ok(())
+    }
 
-                match unchoke.tag {
-                    MessageTag::Unchoke => {
-                        self.choked = false;
-                        assert!(unchoke.payload.is_empty());
+    /// Serves the peer until it disconnects, or until it has every piece and so does the swarm.
+    pub(crate) async fn seed(&mut self) -> anyhow::Result<()> {
+        let mut rechoked = self.swarm.rechoked.subscribe();
+        let mut pex = pex_interval();
+        loop {
+            let npieces = self.swarm.npieces();
+            if self.swarm.is_complete() && (0..npieces).all(|piece_i| self.has_piece(piece_i)) {
+                // two seeds have nothing to say to each other
+                break;
+            }
+            tokio::select! {
+                changed = rechoked.changed() => {
+                    changed.context("swarm went away")?;
+                    self.apply_choke().await?;
+                }
+                msg = self.stream.next() => {
+                    let Some(msg) = msg else {
                         break;
-                    }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
-                    }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
-                    }
-                    MessageTag::Piece => {
-                        // piece that we no longer need/are responsible for
-                    }
-                    MessageTag::Choke => {
-                        anyhow::bail!("peer sent unchoke while unchoked");
-                    }
-                    MessageTag::Bitfield => {
-                        anyhow::bail!("peer sent bitfield after handshake has been completed");
-                    }
+                    };
+                    let msg = msg.context("peer message was invalid")?;
+                    // we are not downloading, so there are no blocks to collect
+                    let _ = self.handle_message(msg).await?;
                 }
+                _ = pex.tick() => self.send_pex().await?,
             }
+        }
+        Ok(())
+    }
 
-            let Ok(block) = tasks.recv().await else {
-                break;
-            };
+    /// Tells the peer we now have piece `piece_i`, unless it has it too and so doesn't care.
+    pub(crate) async fn send_have(&mut self, piece_i: usize) -> anyhow::Result<()> {
+        if self.has_piece(piece_i) {
+            return Ok(());
+        }
+        self.send(MessageTag::Have, (piece_i as u32).to_be_bytes().to_vec())
+            .await
+    }
 
-            let block_size = if block == nblocks - 1 {
-                let md = piece_size % BLOCK_MAX;
-                if md == 0 {
-                    BLOCK_MAX
-                } else {
-                    md
-                }
-            } else {
-                BLOCK_MAX
-            };
-            let mut request = Request::new(
-                piece_i as u32,
-                (block * BLOCK_MAX) as u32,
-                block_size as u32,
-            );
-            let request_bytes = Vec::from(request.as_bytes_mut());
-            self.stream
-                .send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
-                .await
-                .with_context(|| format!("send request for {block}"))?;
+    async fn send(&mut self, tag: MessageTag, payload: Vec<u8>) -> anyhow::Result<()> {
+        self.stream
+            .send(Message { tag, payload })
+            .await
+            .with_context(|| format!("send {tag:?} message"))
+    }
 
-            let mut msg;
-            loop {
-                msg = self
-                    .stream
-                    .next()
+    /// Handles an extended message: either the peer's extended handshake, or a message for one
+    /// of the extensions in our registry.
+    pub(crate) async fn handle_extended(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        let (&id, rest) = payload.split_first().context("empty extended message")?;
+        if id == extension::HANDSHAKE_ID {
+            let (_, handshake, _) = extension::parse::<ExtendedHandshake>(payload)?;
+            if let Some(reqq) = handshake.reqq {
+                self.pipeline.limit(reqq);
+            }
+            if let Some(port) = handshake.p {
+                // a peer that connected to us comes from a port other than the one it listens on
+                let listen_addr = SocketAddr::new(self.addr.ip(), port);
+                self.swarm.update_peer(self.addr, |stats| {
+                    stats.listen_addr.get_or_insert(listen_addr);
+                });
+            }
+            self.extensions = Some(handshake);
+            return Ok(());
+        }
+
+        let Some(ext) = self.swarm.registry.get(id).cloned() else {
+            // not something we advertised; ignore it
+            return Ok(());
+        };
+        let replies = ext
+            .on_message(self.addr, rest)
+            .with_context(|| format!("{} message from peer", ext.name()))?;
+        let their_id = self
+            .extensions
+            .as_ref()
+            .and_then(|theirs| theirs.id_of(ext.name()));
+        if let Some(their_id) = their_id {
+            for reply in replies {
+                self.stream
+                    .send(extension::raw_message(their_id, &reply))
                     .await
-                    .expect("peer always sends a request")
-                    .context("peer request message was invalid")?;
-
-                match msg.tag {
-                    MessageTag::Choke => {
-                        assert!(msg.payload.is_empty());
-                        self.choked = true;
-                        submit.send(block).await.expect("we still hvave a receiver");
-                        continue 'task;
+                    .with_context(|| format!("send {} message", ext.name()))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
+        self.bitfield.has_piece(piece_i)
+    }
+
+    /// Downloads the blocks `scheduler` hands out for this peer, sending each one that arrives to
+    /// `blocks`, until every piece is done.
+    ///
+    /// Up to a pipeline's worth of requests are kept outstanding, and blocks are matched to them
+    /// in whatever order they arrive. Requests the peer drops by choking us go back to the
+    /// scheduler for whoever can take them, and requests for blocks that arrived from another
+    /// peer in endgame are cancelled, as are requests that time out. A peer that keeps timing out
+    /// is given up on. Meanwhile, the peer is told about every piece we finish and about the other
+    /// peers in the swarm, and served what it asks for.
+    pub(crate) async fn download(
+        &mut self,
+        scheduler: &Scheduler,
+        blocks: tokio::sync::mpsc::Sender<Block>,
+    ) -> anyhow::Result<()> {
+        let mut rechoked = self.swarm.rechoked.subscribe();
+        let mut haves = self.swarm.haves.subscribe();
+        let mut cancels = scheduler.cancels();
+        let mut pex = pex_interval();
+        let (snub_after, timeout) = (
+            self.swarm.config.snub_timeout,
+            self.swarm.config.request_timeout,
+        );
+
+        loop {
+            let work = scheduler.notified();
+            tokio::pin!(work);
+            work.as_mut().enable();
+            if scheduler.is_done() {
+                return Ok(());
+            }
+
+            while self.am_interested && !self.choked && self.pipeline.has_room() {
+                let Some(block) = scheduler.next_block(self.addr, &self.bitfield) else {
+                    break;
+                };
+                self.request(block.piece_i, block.begin, block.length)
+                    .await
+                    .with_context(|| format!("send request for {block:?}"))?;
+            }
+
+            let deadline = self.pipeline.deadline(snub_after, timeout);
+            let expiry = tokio::time::sleep_until(
+                deadline.map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std),
+            );
+            tokio::select! {
+                _ = expiry, if deadline.is_some() => {
+                    let expired =
+                        self.pipeline
+                            .expire(std::time::Instant::now(), snub_after, timeout);
+                    for req in expired {
+                        // we're asking someone else, so the peer needn't bother any more
+                        let mut cancel =
+                            Request::new(req.piece_i as u32, req.begin as u32, req.length as u32);
+                        self.send(MessageTag::Cancel, Vec::from(cancel.as_bytes_mut()))
+                            .await?;
+                        scheduler.returned(self.addr, req.piece_i, req.begin);
                     }
-                    MessageTag::Piece => {
+                    anyhow::ensure!(
+                        self.pipeline.timeouts() < self.swarm.config.max_timeouts,
+                        "peer timed out {} times in a row",
+                        self.pipeline.timeouts()
+                    );
+                }
+                msg = self.stream.next() => {
+                    let msg = msg
+                        .context("peer closed connection")?
+                        .context("peer message was invalid")?;
+                    // a piece the peer just got is one more that we may be able to get from it
+                    let new_piece = match msg.tag {
+                        MessageTag::Have => msg.payload[..]
+                            .try_into()
+                            .ok()
+                            .map(|piece_i| u32::from_be_bytes(piece_i) as usize)
+                            .filter(|&piece_i| !self.has_piece(piece_i)),
+                        _ => None,
+                    };
+                    let msg = self.handle_message(msg).await?;
+                    if let Some(piece_i) = new_piece {
+                        scheduler.peer_has(piece_i);
+                    }
+                    if let Some(mut msg) = msg {
                         let piece = Piece::ref_from_bytes(&msg.payload[..])
-                            .expect("always get all Piece response fields from peer");
-
-                        if piece.index() as usize != piece_i
-                            || piece.begin() as usize != block * BLOCK_MAX
-                        {
-                            // piece that we no longer need/are responsible for
-                        } else {
-                            assert_eq!(piece.block().len(), block_size);
-                            break;
+                            .context("piece message must hold index and begin")?;
+                        let (piece_i, begin) = (piece.index() as usize, piece.begin() as usize);
+                        // anything else is a block that we no longer need/are responsible for
+                        if let Some(req) = self.pipeline.take(piece_i, begin) {
+                            anyhow::ensure!(
+                                piece.block().len() == req.length,
+                                "peer sent a block of the wrong size"
+                            );
+                            if scheduler.received(piece_i, begin, req.length) {
+                                let block = Block {
+                                    piece_i,
+                                    begin,
+                                    data: msg.payload.split_off(8),
+                                    peer: self.addr,
+                                };
+                                blocks.send(block).await.ok().context("download ended")?;
+                            }
                         }
                     }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
-                    }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
+                }
+                have = haves.recv() => match have {
+                    Ok(piece_i) => {
+                        self.send_have(piece_i).await?;
+                        self.update_interest().await?;
                     }
-                    MessageTag::Unchoke => {
-                        anyhow::bail!("peer sent unchoke while unchoked");
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        // we don't know which we missed, so tell it about everything again
+                        for piece_i in self.swarm.bitfield().pieces() {
+                            self.send_have(piece_i).await?;
+                        }
+                        self.update_interest().await?;
                     }
-                    MessageTag::Bitfield => {
-                        anyhow::bail!("peer sent bitfield after handshake has been completed");
+                    Err(broadcast::error::RecvError::Closed) => {}
+                },
+                changed = rechoked.changed() => {
+                    changed.context("swarm went away")?;
+                    self.apply_choke().await?;
+                }
+                cancel = cancels.recv() => {
+                    // if we missed some, their blocks are just wasted when they arrive
+                    if let Ok((piece_i, begin)) = cancel {
+                        if let Some(req) = self.pipeline.cancel(piece_i, begin) {
+                            let mut cancel =
+                                Request::new(piece_i as u32, begin as u32, req.length as u32);
+                            self.send(MessageTag::Cancel, Vec::from(cancel.as_bytes_mut()))
+                                .await?;
+                        }
                     }
                 }
+                _ = pex.tick() => self.send_pex().await?,
+                _ = &mut work => {}
             }
 
-            finish
-                .send(msg)
-                .await
-                .expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
+            if self.choked {
+                // a choking peer discards our requests, so someone else will have to serve them
+                for req in self.pipeline.drain() {
+                    scheduler.returned(self.addr, req.piece_i, req.begin);
+                }
+            }
         }
+    }
 
+    /// Asks the peer for `length` bytes at `begin` in piece `piece_i`.
+    async fn request(&mut self, piece_i: usize, begin: usize, length: usize) -> anyhow::Result<()> {
+        let mut request = Request::new(piece_i as u32, begin as u32, length as u32);
+        self.send(MessageTag::Request, Vec::from(request.as_bytes_mut()))
+            .await?;
+        self.pipeline.push(piece_i, begin, length);
         Ok(())
     }
 }
 
+/// Ticks every [`pex::PEX_INTERVAL`], starting one interval from now, so that peer exchange
+/// messages go out no more often than allowed.
+fn pex_interval() -> tokio::time::Interval {
+    let start = tokio::time::Instant::now() + pex::PEX_INTERVAL;
+    tokio::time::interval_at(start, pex::PEX_INTERVAL)
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.swarm
+            .peers
+            .lock()
+            .expect("not poisoned")
+            .remove(&self.addr);
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Bitfield {
     payload: Vec<u8>,
 }
@@ -249,10 +629,10 @@ impl Bitfield {
             return false;
         };
 
-        byte & 1u8.rotate_right((bit_i + 1) as u32) != 0
+        byte & 1u8.rotate_right(bit_i + 1) != 0
     }
 
-    pub(crate) fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
         self.payload.iter().enumerate().flat_map(|(byte_i, byte)| {
             (0..u8::BITS).filter_map(move |bit_i| {
                 let piece_i = byte_i * (u8::BITS as usize) + (bit_i as usize);
@@ -262,11 +642,57 @@ impl Bitfield {
         })
     }
 
-    pub fn from_payload(payload: Vec<u8>) -> Self {
-        Self { payload }
+    /// The bitfield of a torrent with `npieces` pieces, as sent in a Bitfield message: it must be
+    /// exactly long enough for them, and the spare bits at the end must be clear.
+    pub fn from_payload(payload: Vec<u8>, npieces: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            payload.len() == npieces.div_ceil(u8::BITS as usize),
+            "bitfield of {} bytes for {npieces} pieces",
+            payload.len()
+        );
+        let bitfield = Self { payload };
+        anyhow::ensure!(
+            bitfield.pieces().all(|piece_i| piece_i < npieces),
+            "bitfield has spare bits set"
+        );
+        Ok(bitfield)
+    }
+
+    /// An empty bitfield with room for `npieces` pieces.
+    pub fn new(npieces: usize) -> Self {
+        Self {
+            payload: vec![0; npieces.div_ceil(u8::BITS as usize)],
+        }
+    }
+
+    /// Marks piece `piece_i` as present. Pieces beyond the end of the bitfield are ignored.
+    pub(crate) fn set_piece(&mut self, piece_i: usize) {
+        let byte_i = piece_i / u8::BITS as usize;
+        let bit_i = (piece_i % (u8::BITS as usize)) as u32;
+
+        if let Some(byte) = self.payload.get_mut(byte_i) {
+            *byte |= 1u8.rotate_right(bit_i + 1);
+        }
+    }
+
+    pub(crate) fn clear_piece(&mut self, piece_i: usize) {
+        let byte_i = piece_i / u8::BITS as usize;
+        let bit_i = (piece_i % (u8::BITS as usize)) as u32;
+
+        if let Some(byte) = self.payload.get_mut(byte_i) {
+            *byte &= !1u8.rotate_right(bit_i + 1);
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.payload
     }
 }
 
+/// The reserved bit for the extension protocol is the 20th bit from the right.
+const EXTENSION_BYTE: usize = 5;
+const EXTENSION_BIT: u8 = 0x10;
+
 #[repr(C)]
 pub struct Handshake {
     pub length: u8,
@@ -287,6 +713,17 @@ impl Handshake {
         }
     }
 
+    /// Advertises support for the extension protocol (BEP 10).
+    pub fn with_extensions(mut self) -> Self {
+        self.reserved[EXTENSION_BYTE] |= EXTENSION_BIT;
+        self
+    }
+
+    /// Whether the other side advertised support for the extension protocol (BEP 10).
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_BYTE] & EXTENSION_BIT != 0
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let handshake_bytes = self as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
         // Safety: Handshake is POD with repr(c)
@@ -325,6 +762,14 @@ impl Request {
         u32::from_be_bytes(self.length)
     }
 
+    pub fn ref_from_bytes(data: &[u8]) -> Option<&Self> {
+        if data.len() != std::mem::size_of::<Self>() {
+            return None;
+        }
+        // Safety: Request is POD with repr(c) and alignment 1, and data is exactly its size
+        Some(unsafe { &*(data.as_ptr() as *const Self) })
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let req_bytes = self as *mut Request as *mut [u8; std::mem::size_of::<Request>()];
         // Safety: Handshake is POD with repr(c)
@@ -379,6 +824,7 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Extended = 20,
 }
 
 #[derive(Debug, Clone)]
@@ -392,6 +838,12 @@ pub struct MessageFramer;
 // const MAX: usize = 1 << 16;
 const MAX: usize = 1 << 16;
 
+// a Piece message carries the index and begin, and the block
+const _: () = assert!(
+    1 + 8 + MAX_REQUEST <= MAX,
+    "served blocks must fit in a frame"
+);
+
 impl Decoder for MessageFramer {
     type Item = Message;
     type Error = std::io::Error;
@@ -453,6 +905,7 @@ impl Decoder for MessageFramer {
             6 => MessageTag::Request,
             7 => MessageTag::Piece,
             8 => MessageTag::Cancel,
+            20 => MessageTag::Extended,
             tag => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
@@ -513,6 +966,34 @@ fn bitfield_has() {
     assert!(bf.has_piece(15));
 }
 
+#[test]
+fn bitfield_set_clear() {
+    let mut bf = Bitfield::new(10);
+    assert_eq!(bf.as_bytes(), &[0, 0]);
+
+    bf.set_piece(0);
+    bf.set_piece(9);
+    assert_eq!(bf.as_bytes(), &[0b10000000, 0b01000000]);
+
+    bf.clear_piece(0);
+    assert!(!bf.has_piece(0));
+    assert!(bf.has_piece(9));
+
+    // a piece index from a peer mustn't make the bitfield grow
+    bf.set_piece(u32::MAX as usize);
+    assert_eq!(bf.as_bytes().len(), 2);
+}
+
+#[test]
+fn bitfield_from_payload_checks_length() {
+    let bf = Bitfield::from_payload(vec![0b10000000, 0b01000000], 10).unwrap();
+    assert_eq!(bf.pieces().collect::<Vec<_>>(), vec![0, 9]);
+    assert!(Bitfield::from_payload(vec![0b10000000], 10).is_err());
+    assert!(Bitfield::from_payload(vec![0, 0, 0], 10).is_err());
+    // piece 10 doesn't exist
+    assert!(Bitfield::from_payload(vec![0, 0b00100000], 10).is_err());
+}
+
 #[test]
 fn bitfield_iter() {
     let bf = Bitfield {
@@ -530,3 +1011,61 @@ fn bitfield_iter() {
     assert_eq!(pieces.next(), Some(15));
     assert_eq!(pieces.next(), None);
 }
+
+#[tokio::test]
+async fn peer_serves_requests() {
+    use crate::create::create_test_torrent;
+    use crate::storage::Storage;
+    use crate::swarm::SwarmConfig;
+    use crate::verify::verify_storage;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data");
+    let data: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+    let t = create_test_torrent(&path, &data, Some(1 << 14)).await;
+    let storage = Storage::existing(&t, &path).unwrap();
+    let have = verify_storage(&t, &storage).await.unwrap().bitfield();
+    let swarm = Arc::new(Swarm::new(&t, storage, have, SwarmConfig::default()).unwrap());
+
+    // a leecher that has nothing and asks for the second block of the last piece
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = t.info_hash();
+    let leecher = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = Handshake::new(info_hash, [1; 20]);
+        stream.read_exact(handshake.as_bytes_mut()).await.unwrap();
+        let mut handshake = Handshake::new(info_hash, [1; 20]);
+        stream.write_all(handshake.as_bytes_mut()).await.unwrap();
+        let mut stream = Framed::new(stream, MessageFramer);
+
+        let bitfield = stream.next().await.unwrap().unwrap();
+        assert_eq!(bitfield.tag, MessageTag::Bitfield);
+        assert_eq!(bitfield.payload, vec![0b1110_0000]);
+
+        let interested = Message {
+            tag: MessageTag::Interested,
+            payload: Vec::new(),
+        };
+        stream.send(interested).await.unwrap();
+        let mut request = Request::new(2, 1000, 500);
+        let request = Message {
+            tag: MessageTag::Request,
+            payload: request.as_bytes_mut().to_vec(),
+        };
+        stream.send(request).await.unwrap();
+        let unchoke = stream.next().await.unwrap().unwrap();
+        assert_eq!(unchoke.tag, MessageTag::Unchoke);
+        let piece = stream.next().await.unwrap().unwrap();
+        assert_eq!(piece.tag, MessageTag::Piece);
+        let piece = Piece::ref_from_bytes(&piece.payload).unwrap();
+        assert_eq!((piece.index(), piece.begin()), (2, 1000));
+        piece.block().to_vec()
+    });
+
+    let mut peer = Peer::new(addr, swarm).await.unwrap();
+    assert!(!peer.has_piece(0));
+    peer.seed().await.unwrap();
+    let block = leecher.await.unwrap();
+    assert_eq!(block, &data[2 * (1 << 14) + 1000..][..500]);
+}
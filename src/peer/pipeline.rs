@@ -0,0 +1,281 @@
+//! The requests we have outstanding with one peer, and how many we keep outstanding at once.
+
+use std::time::{Duration, Instant};
+
+use crate::BLOCK_MAX;
+
+/// We aim to have enough requests outstanding to keep the peer busy for this long, so that its
+/// upload never waits on a round trip to us.
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+
+/// How often the download rate behind the pipeline depth is re-measured.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// A block we asked the peer for and have not received yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outstanding {
+    pub piece_i: usize,
+    pub begin: usize,
+    pub length: usize,
+    pub sent: Instant,
+}
+
+/// The requests outstanding with a peer.
+///
+/// The depth starts out at a configured minimum and follows the rate the peer actually delivers
+/// at, capped by our own maximum and by the `reqq` the peer advertises.
+///
+/// A peer that has requests outstanding but delivers nothing for a while is snubbed: it is only
+/// sent one request at a time until it delivers again. Requests that go unanswered for longer
+/// still time out, so they can be asked of someone else.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    min_depth: usize,
+    max_depth: usize,
+    depth: usize,
+    outstanding: Vec<Outstanding>,
+    /// Smoothed download rate, in bytes per second.
+    rate: f64,
+    window_start: Instant,
+    window_bytes: usize,
+    /// When the peer last delivered a block, or we started waiting on it to.
+    last_block: Instant,
+    snubbed: bool,
+    /// Times requests expired since the peer last delivered a block.
+    timeouts: usize,
+}
+
+impl Pipeline {
+    pub fn new(min_depth: usize, max_depth: usize) -> Self {
+        let min_depth = min_depth.max(1);
+        Self {
+            min_depth,
+            max_depth: max_depth.max(min_depth),
+            depth: min_depth,
+            outstanding: Vec::new(),
+            rate: 0.0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+            last_block: Instant::now(),
+            snubbed: false,
+            timeouts: 0,
+        }
+    }
+
+    /// Caps the depth at the number of requests the peer says it will queue.
+    pub fn limit(&mut self, reqq: usize) {
+        let reqq = reqq.max(1);
+        self.max_depth = self.max_depth.min(reqq);
+        self.min_depth = self.min_depth.min(reqq);
+        self.depth = self.depth.clamp(self.min_depth, self.max_depth);
+    }
+
+    /// How many requests we want outstanding at once.
+    pub fn depth(&self) -> usize {
+        if self.snubbed {
+            1
+        } else {
+            self.depth
+        }
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.outstanding.len() < self.depth()
+    }
+
+    pub fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    /// How many times in a row requests expired without the peer delivering anything.
+    pub fn timeouts(&self) -> usize {
+        self.timeouts
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    /// Records that we requested `length` bytes at `begin` in piece `piece_i`.
+    pub fn push(&mut self, piece_i: usize, begin: usize, length: usize) {
+        if self.outstanding.is_empty() {
+            // the peer can't be slow to deliver what we didn't ask for
+            self.last_block = Instant::now();
+        }
+        self.outstanding.push(Outstanding {
+            piece_i,
+            begin,
+            length,
+            sent: Instant::now(),
+        });
+    }
+
+    /// Matches a block that arrived to the request for it, in whatever order the peer answers.
+    ///
+    /// Returns `None` for a block we did not (or no longer) ask for.
+    pub fn take(&mut self, piece_i: usize, begin: usize) -> Option<Outstanding> {
+        self.take_at(piece_i, begin, Instant::now())
+    }
+
+    fn take_at(&mut self, piece_i: usize, begin: usize, now: Instant) -> Option<Outstanding> {
+        let i = self
+            .outstanding
+            .iter()
+            .position(|req| req.piece_i == piece_i && req.begin == begin)?;
+        let req = self.outstanding.remove(i);
+        self.last_block = now;
+        self.snubbed = false;
+        self.timeouts = 0;
+        self.record(req.length, now);
+        Some(req)
+    }
+
+    /// When [`Pipeline::expire`] next has something to do, if anything is outstanding.
+    pub fn deadline(&self, snub_after: Duration, timeout: Duration) -> Option<Instant> {
+        let oldest = self.outstanding.iter().map(|req| req.sent).min()?;
+        let expiry = oldest + timeout;
+        if self.snubbed {
+            Some(expiry)
+        } else {
+            Some(expiry.min(self.last_block + snub_after))
+        }
+    }
+
+    /// Snubs the peer if it hasn't delivered anything for `snub_after`, and forgets the requests
+    /// that have been outstanding for `timeout`, returning them.
+    pub fn expire(
+        &mut self,
+        now: Instant,
+        snub_after: Duration,
+        timeout: Duration,
+    ) -> Vec<Outstanding> {
+        if self.outstanding.is_empty() {
+            return Vec::new();
+        }
+        if now.saturating_duration_since(self.last_block) >= snub_after {
+            self.snubbed = true;
+        }
+        let (expired, outstanding) = self
+            .outstanding
+            .iter()
+            .partition(|req| now.saturating_duration_since(req.sent) >= timeout);
+        self.outstanding = outstanding;
+        if !expired.is_empty() {
+            self.timeouts += 1;
+        }
+        expired
+    }
+
+    /// Forgets the request for the block at `begin` in piece `piece_i`, which we are about to
+    /// cancel.
+    pub fn cancel(&mut self, piece_i: usize, begin: usize) -> Option<Outstanding> {
+        let i = self
+            .outstanding
+            .iter()
+            .position(|req| req.piece_i == piece_i && req.begin == begin)?;
+        Some(self.outstanding.remove(i))
+    }
+
+    /// Forgets every outstanding request, e.g. because the peer choked us and so dropped them.
+    pub fn drain(&mut self) -> Vec<Outstanding> {
+        std::mem::take(&mut self.outstanding)
+    }
+
+    /// Adds `bytes` received at `now` to the rate measurement, and resizes the pipeline to what
+    /// that rate can keep busy.
+    fn record(&mut self, bytes: usize, now: Instant) {
+        self.window_bytes += bytes;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        let measured = self.window_bytes as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            measured
+        } else {
+            (self.rate + measured) / 2.0
+        };
+        self.window_start = now;
+        self.window_bytes = 0;
+
+        let wanted = (self.rate * QUEUE_TIME.as_secs_f64() / BLOCK_MAX as f64).ceil() as usize;
+        self.depth = wanted.clamp(self.min_depth, self.max_depth);
+    }
+}
+
+#[test]
+fn pipeline_matches_out_of_order_and_adapts() {
+    let mut pipeline = Pipeline::new(2, 100);
+    pipeline.push(0, 0, BLOCK_MAX);
+    pipeline.push(0, BLOCK_MAX, BLOCK_MAX);
+    assert!(!pipeline.has_room());
+
+    let start = pipeline.window_start;
+    let second = pipeline.take_at(0, BLOCK_MAX, start).unwrap();
+    assert_eq!((second.piece_i, second.begin), (0, BLOCK_MAX));
+    assert!(pipeline.take_at(0, BLOCK_MAX, start).is_none());
+    assert!(pipeline.take_at(1, 0, start).is_none());
+    assert_eq!(pipeline.len(), 1);
+
+    // 20 blocks a second keeps 60 blocks busy for the queue time
+    pipeline.take_at(0, 0, start).unwrap();
+    for i in 0..17 {
+        pipeline.push(1, i * BLOCK_MAX, BLOCK_MAX);
+        pipeline.take_at(1, i * BLOCK_MAX, start).unwrap();
+    }
+    pipeline.push(2, 0, BLOCK_MAX);
+    pipeline.take_at(2, 0, start + RATE_WINDOW).unwrap();
+    assert_eq!(pipeline.depth(), 60);
+
+    // but never more than the peer will queue
+    pipeline.limit(50);
+    assert_eq!(pipeline.depth(), 50);
+
+    pipeline.push(3, 0, BLOCK_MAX);
+    pipeline.push(3, BLOCK_MAX, BLOCK_MAX);
+    assert!(pipeline.cancel(3, 0).is_some());
+    assert!(pipeline.cancel(3, 0).is_none());
+    assert_eq!(pipeline.drain().len(), 1);
+    assert!(pipeline.is_empty());
+}
+
+#[test]
+fn pipeline_snubs_and_expires() {
+    let snub_after = Duration::from_secs(10);
+    let timeout = Duration::from_secs(30);
+    let mut pipeline = Pipeline::new(4, 100);
+    assert!(pipeline.deadline(snub_after, timeout).is_none());
+    pipeline.push(0, 0, BLOCK_MAX);
+    pipeline.push(0, BLOCK_MAX, BLOCK_MAX);
+    let start = pipeline.outstanding[0].sent;
+    assert!(pipeline.deadline(snub_after, timeout).unwrap() <= start + snub_after);
+
+    assert!(pipeline.expire(start, snub_after, timeout).is_empty());
+    assert!(!pipeline.is_snubbed());
+    assert!(pipeline
+        .expire(start + snub_after, snub_after, timeout)
+        .is_empty());
+    assert!(pipeline.is_snubbed());
+    assert_eq!(pipeline.depth(), 1);
+    assert!(!pipeline.has_room());
+
+    let expired = pipeline.expire(
+        start + timeout + Duration::from_secs(1),
+        snub_after,
+        timeout,
+    );
+    assert_eq!(expired.len(), 2);
+    assert_eq!(pipeline.timeouts(), 1);
+    assert!(pipeline.is_empty());
+
+    // delivering anything makes up for it
+    pipeline.push(1, 0, BLOCK_MAX);
+    pipeline.take(1, 0).unwrap();
+    assert!(!pipeline.is_snubbed());
+    assert_eq!(pipeline.timeouts(), 0);
+    assert_eq!(pipeline.depth(), 4);
+}
@@ -0,0 +1,271 @@
+//! Peer exchange (`ut_pex`, BEP 11): learning about other members of the swarm from the peers we
+//! are connected to, and telling them about ours.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::extension::Extension;
+
+/// The name the extension is advertised under.
+pub const NAME: &str = "ut_pex";
+
+/// How often we send a peer the changes to our peer list; the BEP allows at most once a minute.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A message from a peer that arrives sooner than this after its previous one is ignored. It is a
+/// little shorter than [`PEX_INTERVAL`] so that timer jitter doesn't cost us messages.
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(50);
+
+/// The most peers added, and the most dropped, in one message.
+const MAX_PEERS: usize = 50;
+
+/// The most peers we hold on to before someone takes them.
+const MAX_DISCOVERED: usize = 1000;
+
+/// The peer prefers encrypted connections.
+pub const PREFERS_ENCRYPTION: u8 = 0x01;
+/// The peer is a seed, or only uploads.
+pub const SEED: u8 = 0x02;
+/// The peer supports uTP.
+pub const UTP: u8 = 0x04;
+/// The peer supports holepunching.
+pub const HOLEPUNCH: u8 = 0x08;
+/// We connected to the peer, so it accepts connections.
+pub const REACHABLE: u8 = 0x10;
+
+/// A `ut_pex` message: the peers that joined and left since the previous one, in compact form.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: ByteBuf,
+
+    /// One byte of flags for every peer in `added`.
+    #[serde(default, rename = "added.f")]
+    pub added_f: ByteBuf,
+
+    #[serde(default)]
+    pub dropped: ByteBuf,
+
+    #[serde(default)]
+    pub added6: ByteBuf,
+
+    /// One byte of flags for every peer in `added6`.
+    #[serde(default, rename = "added6.f")]
+    pub added6_f: ByteBuf,
+
+    #[serde(default)]
+    pub dropped6: ByteBuf,
+}
+
+impl PexMessage {
+    /// A message announcing the `added` peers, with their flags, and the `dropped` ones.
+    pub fn new(added: &[(SocketAddr, u8)], dropped: &[SocketAddr]) -> Self {
+        let mut msg = Self::default();
+        for &(addr, flags) in added {
+            match addr {
+                SocketAddr::V4(_) => {
+                    msg.added.extend(encode_addr(addr));
+                    msg.added_f.push(flags);
+                }
+                SocketAddr::V6(_) => {
+                    msg.added6.extend(encode_addr(addr));
+                    msg.added6_f.push(flags);
+                }
+            }
+        }
+        for &addr in dropped {
+            match addr {
+                SocketAddr::V4(_) => msg.dropped.extend(encode_addr(addr)),
+                SocketAddr::V6(_) => msg.dropped6.extend(encode_addr(addr)),
+            }
+        }
+        msg
+    }
+
+    /// The peers that joined, with their flags (0 if the sender left them out).
+    pub fn added(&self) -> Vec<(SocketAddr, u8)> {
+        let v4 = decode_addrs(&self.added, 6).zip(flags(&self.added_f));
+        let v6 = decode_addrs(&self.added6, 18).zip(flags(&self.added6_f));
+        v4.chain(v6).collect()
+    }
+
+    /// The peers that left.
+    pub fn dropped(&self) -> Vec<SocketAddr> {
+        decode_addrs(&self.dropped, 6)
+            .chain(decode_addrs(&self.dropped6, 18))
+            .collect()
+    }
+}
+
+/// The flags in `flags`, followed by zeroes for peers it has no flags for.
+fn flags(flags: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    flags.iter().copied().chain(std::iter::repeat(0))
+}
+
+/// Encodes `addr` in compact form: the IP address, then the port.
+fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut compact = match addr.ip() {
+        std::net::IpAddr::V4(ip) => ip.octets().to_vec(),
+        std::net::IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    compact.extend(addr.port().to_be_bytes());
+    compact
+}
+
+/// Decodes compact addresses of `len` bytes each, ignoring a trailing partial one.
+fn decode_addrs(compact: &[u8], len: usize) -> impl Iterator<Item = SocketAddr> + '_ {
+    compact.chunks_exact(len).filter_map(|compact| {
+        let (ip, port) = compact.split_at(compact.len() - 2);
+        let port = u16::from_be_bytes([port[0], port[1]]);
+        let ip = match ip.len() {
+            4 => Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?).into(),
+            16 => Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?).into(),
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, port))
+    })
+}
+
+/// What we have told one peer about our peer list, so that only changes are sent.
+#[derive(Debug, Clone, Default)]
+pub struct PexSender {
+    sent: HashSet<SocketAddr>,
+}
+
+impl PexSender {
+    /// The message that brings the peer up to date with `current`, the peers we are connected to
+    /// and their flags, if anything changed.
+    ///
+    /// At most [`MAX_PEERS`] are added and dropped at once; the rest are left for later messages.
+    pub fn update(&mut self, current: &[(SocketAddr, u8)]) -> Option<PexMessage> {
+        let added: Vec<(SocketAddr, u8)> = current
+            .iter()
+            .filter(|(addr, _)| !self.sent.contains(addr))
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .iter()
+            .filter(|&sent| !current.iter().any(|(addr, _)| addr == sent))
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        self.sent.extend(added.iter().map(|&(addr, _)| addr));
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        Some(PexMessage::new(&added, &dropped))
+    }
+}
+
+/// Collects the peers that the peers we are connected to tell us about with `ut_pex`.
+#[derive(Debug, Default)]
+pub struct UtPex {
+    discovered: Mutex<Vec<SocketAddr>>,
+    /// When each peer last sent us a message we accepted.
+    last_received: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl UtPex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The peers we were told about since the last call.
+    pub fn take_discovered(&self) -> Vec<SocketAddr> {
+        std::mem::take(&mut *self.discovered.lock().expect("not poisoned"))
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_message(&self, peer: SocketAddr, payload: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+        let now = Instant::now();
+        {
+            let mut last_received = self.last_received.lock().expect("not poisoned");
+            last_received.retain(|_, at| now.duration_since(*at) < MIN_RECEIVE_INTERVAL);
+            if last_received.contains_key(&peer) {
+                // the peer sends more often than it may
+                return Ok(Vec::new());
+            }
+            last_received.insert(peer, now);
+        }
+
+        let msg: PexMessage =
+            serde_bencode::from_bytes(payload).context("decode ut_pex message")?;
+        let mut discovered = self.discovered.lock().expect("not poisoned");
+        let room = MAX_DISCOVERED.saturating_sub(discovered.len());
+        discovered.extend(
+            msg.added()
+                .into_iter()
+                .take(MAX_PEERS.min(room))
+                .map(|(addr, _)| addr),
+        );
+        Ok(Vec::new())
+    }
+}
+
+#[test]
+fn pex_sender_sends_only_changes() {
+    let v4 = |port| SocketAddr::from(([10, 0, 0, 1], port));
+    let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 6881));
+    let mut sender = PexSender::default();
+
+    let msg = sender
+        .update(&[(v4(1), SEED | REACHABLE), (v6, 0)])
+        .unwrap();
+    let encoded = serde_bencode::to_bytes(&msg).unwrap();
+    let msg: PexMessage = serde_bencode::from_bytes(&encoded).unwrap();
+    assert_eq!(msg.added(), vec![(v4(1), SEED | REACHABLE), (v6, 0)]);
+    assert!(msg.dropped().is_empty());
+    assert!(sender.update(&[(v4(1), SEED), (v6, 0)]).is_none());
+
+    let msg = sender.update(&[(v4(2), 0), (v6, 0)]).unwrap();
+    assert_eq!(msg.added(), vec![(v4(2), 0)]);
+    assert_eq!(msg.dropped(), vec![v4(1)]);
+
+    // a big swarm takes more than one message
+    let many: Vec<(SocketAddr, u8)> = (100..100 + MAX_PEERS as u16 + 10)
+        .map(|port| (v4(port), 0))
+        .collect();
+    let first = sender.update(&many).unwrap();
+    assert_eq!(first.added().len(), MAX_PEERS);
+    assert_eq!(first.dropped().len(), 2);
+    let rest = sender.update(&many).unwrap();
+    assert_eq!(rest.added().len(), 10);
+    assert!(rest.dropped().is_empty());
+    assert!(sender.update(&many).is_none());
+}
+
+#[test]
+fn ut_pex_collects_peers_and_ignores_floods() {
+    let pex = UtPex::new();
+    let sender = SocketAddr::from(([10, 0, 0, 1], 6881));
+    let added = SocketAddr::from(([10, 0, 0, 2], 6881));
+    let msg = serde_bencode::to_bytes(&PexMessage::new(&[(added, 0)], &[])).unwrap();
+
+    assert!(pex.on_message(sender, &msg).unwrap().is_empty());
+    assert_eq!(pex.take_discovered(), vec![added]);
+    assert!(pex.take_discovered().is_empty());
+
+    // too soon after the previous one
+    pex.on_message(sender, &msg).unwrap();
+    assert!(pex.take_discovered().is_empty());
+    let other = SocketAddr::from(([10, 0, 0, 3], 6881));
+    pex.on_message(other, &msg).unwrap();
+    assert_eq!(pex.take_discovered(), vec![added]);
+}
@@ -0,0 +1,161 @@
+//! Which piece to start on next: the rarest one among the peers we are connected to.
+
+use crate::peer::Bitfield;
+
+/// Keeps count of how many connected peers have each piece, and picks pieces to start on.
+///
+/// The rarest piece is picked, so that pieces few peers have get spread before those peers leave,
+/// with ties broken at random so that downloaders don't all go for the same piece. Until we have
+/// a few pieces, though, a random piece is picked instead: a common piece downloads faster, and
+/// we need something to offer other peers before they will trade with us.
+#[derive(Debug, Clone)]
+pub struct Picker {
+    /// How many connected peers have each piece.
+    availability: Vec<u32>,
+    /// The pieces nobody has started on, and that we don't have.
+    wanted: Vec<bool>,
+    have: usize,
+    random_first: usize,
+}
+
+impl Picker {
+    /// A picker for the pieces we don't `have`, which picks at random until we have
+    /// `random_first` pieces.
+    pub fn new(have: &Bitfield, npieces: usize, random_first: usize) -> Self {
+        let wanted: Vec<bool> = (0..npieces)
+            .map(|piece_i| !have.has_piece(piece_i))
+            .collect();
+        Self {
+            availability: vec![0; npieces],
+            have: wanted.iter().filter(|&&wanted| !wanted).count(),
+            wanted,
+            random_first,
+        }
+    }
+
+    /// Counts the pieces of a peer that connected.
+    pub fn add_peer(&mut self, has: &Bitfield) {
+        for piece_i in has.pieces() {
+            if let Some(count) = self.availability.get_mut(piece_i) {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Stops counting the pieces of a peer that went away.
+    pub fn remove_peer(&mut self, has: &Bitfield) {
+        for piece_i in has.pieces() {
+            if let Some(count) = self.availability.get_mut(piece_i) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Counts a piece a connected peer just got.
+    pub fn peer_has(&mut self, piece_i: usize) {
+        if let Some(count) = self.availability.get_mut(piece_i) {
+            *count += 1;
+        }
+    }
+
+    /// How many connected peers have piece `piece_i`.
+    pub fn availability(&self, piece_i: usize) -> u32 {
+        self.availability.get(piece_i).copied().unwrap_or(0)
+    }
+
+    /// Picks a piece to start on among those in `has`, and takes it off the wanted list.
+    pub fn pick(&mut self, has: &Bitfield) -> Option<usize> {
+        let random = self.have < self.random_first;
+        let mut best = None;
+        let mut ties = 0;
+        for piece_i in has.pieces() {
+            if !self.wanted.get(piece_i).copied().unwrap_or(false) {
+                continue;
+            }
+            // when picking at random, every piece ranks the same
+            let rank = if random {
+                0
+            } else {
+                self.availability[piece_i]
+            };
+            match best {
+                Some((_, best_rank)) if rank > best_rank => continue,
+                Some((_, best_rank)) if rank == best_rank => {
+                    // keep each tied piece with equal probability
+                    ties += 1;
+                    if !crate::random_u64().is_multiple_of(ties) {
+                        continue;
+                    }
+                }
+                _ => ties = 1,
+            }
+            best = Some((piece_i, rank));
+        }
+
+        let (piece_i, _) = best?;
+        self.wanted[piece_i] = false;
+        Some(piece_i)
+    }
+
+    /// Puts a piece that was started on back on the wanted list, e.g. because it failed to
+    /// verify.
+    pub fn unpick(&mut self, piece_i: usize) {
+        if let Some(wanted) = self.wanted.get_mut(piece_i) {
+            *wanted = true;
+        }
+    }
+
+    /// Records that we now have a piece.
+    pub fn finished(&mut self) {
+        self.have += 1;
+    }
+
+    /// Whether there are pieces nobody has started on.
+    pub fn any_wanted(&self) -> bool {
+        self.wanted.contains(&true)
+    }
+}
+
+#[test]
+fn picker_picks_rarest_after_random_first() {
+    let bitfield = |pieces: &[usize]| {
+        let mut bitfield = Bitfield::new(4);
+        pieces
+            .iter()
+            .for_each(|&piece_i| bitfield.set_piece(piece_i));
+        bitfield
+    };
+    let mut have = Bitfield::new(4);
+    have.set_piece(3);
+    let all = bitfield(&[0, 1, 2, 3]);
+
+    let mut picker = Picker::new(&have, 4, 0);
+    picker.add_peer(&all);
+    picker.add_peer(&bitfield(&[0, 2]));
+    let leaving = bitfield(&[0, 1, 2]);
+    picker.add_peer(&leaving);
+    picker.remove_peer(&leaving);
+    assert_eq!(picker.availability(0), 2);
+    assert_eq!(picker.availability(1), 1);
+
+    // 1 is the rarest; then another peer gets 0, which leaves 2 rarer
+    assert_eq!(picker.pick(&all), Some(1));
+    picker.peer_has(0);
+    assert_eq!(picker.pick(&all), Some(2));
+    assert_eq!(picker.pick(&all), Some(0));
+    assert_eq!(picker.pick(&all), None);
+    assert!(!picker.any_wanted());
+    picker.unpick(1);
+    assert_eq!(picker.pick(&bitfield(&[1])), Some(1));
+
+    // while picking at random, the rare piece is no more likely than the others
+    let mut picked = [0; 4];
+    for _ in 0..300 {
+        let mut picker = Picker::new(&have, 4, 2);
+        picker.add_peer(&all);
+        picker.add_peer(&bitfield(&[0, 2]));
+        picked[picker.pick(&all).unwrap()] += 1;
+    }
+    assert!(picked[..3].iter().all(|&n| n > 50), "{picked:?}");
+    assert_eq!(picked[3], 0);
+}
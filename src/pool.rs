@@ -0,0 +1,210 @@
+//! The peers we know of for a torrent, and which of them to connect to.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long to wait before retrying a peer that failed once; doubled for every further failure.
+const RETRY_BASE: Duration = Duration::from_secs(30);
+
+/// A peer that failed this many times in a row is forgotten.
+const MAX_FAILURES: u32 = 5;
+
+/// Every peer we know of, from trackers, the DHT, other peers, or because it connected to us.
+///
+/// The pool hands out peers to connect to until a target number of connections is reached.
+/// A peer that can't be reached, or that drops us, is retried later with exponential backoff, and
+/// forgotten once it has failed too often.
+#[derive(Debug, Clone)]
+pub struct PeerPool {
+    candidates: HashMap<SocketAddr, Candidate>,
+    target: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    state: State,
+    failures: u32,
+    /// When the peer may be tried again.
+    retry_at: Instant,
+    /// Whether the address is one the peer accepts connections on, rather than the one it
+    /// happened to connect to us from.
+    dialable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Connecting,
+    Connected,
+}
+
+impl PeerPool {
+    /// A pool that aims for `target` connections.
+    pub fn new(target: usize) -> Self {
+        Self {
+            candidates: HashMap::new(),
+            target: target.max(1),
+        }
+    }
+
+    /// Adds the peers in `addrs` that we didn't know of yet.
+    pub fn add(&mut self, addrs: impl IntoIterator<Item = SocketAddr>, now: Instant) {
+        for addr in addrs {
+            let candidate = self.candidates.entry(addr).or_insert(Candidate {
+                state: State::Idle,
+                failures: 0,
+                retry_at: now,
+                dialable: true,
+            });
+            candidate.dialable = true;
+        }
+    }
+
+    /// Picks peers to connect to, as many as it takes to reach the target, and marks them as
+    /// connecting.
+    pub fn to_connect(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let wanted = self.target.saturating_sub(self.connections());
+        let mut ready: Vec<(&SocketAddr, &mut Candidate)> = self
+            .candidates
+            .iter_mut()
+            .filter(|(_, candidate)| candidate.state == State::Idle && candidate.retry_at <= now)
+            .collect();
+        // peers that never failed us first
+        ready.sort_by_key(|(_, candidate)| candidate.failures);
+        ready
+            .into_iter()
+            .take(wanted)
+            .map(|(&addr, candidate)| {
+                candidate.state = State::Connecting;
+                addr
+            })
+            .collect()
+    }
+
+    /// Records that we are connected to `addr`, which we dialed. Returns `false` if we already
+    /// were, so the new connection is a duplicate.
+    pub fn connected(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        self.connect(addr, now, true)
+    }
+
+    /// Records that the peer at `addr` connected to us. Unless we knew of `addr` already, it is
+    /// only the port the peer connected from, so it is forgotten rather than dialed once the
+    /// connection ends. Returns `false` if we already were connected to `addr`.
+    pub fn accepted(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        self.connect(addr, now, false)
+    }
+
+    fn connect(&mut self, addr: SocketAddr, now: Instant, dialable: bool) -> bool {
+        let candidate = self.candidates.entry(addr).or_insert(Candidate {
+            state: State::Idle,
+            failures: 0,
+            retry_at: now,
+            dialable,
+        });
+        if candidate.state == State::Connected {
+            return false;
+        }
+        candidate.state = State::Connected;
+        // failures only count in a row, so a good peer that comes and goes isn't given up on
+        candidate.failures = 0;
+        true
+    }
+
+    /// Records that connecting to `addr` failed, or that the connection to it was lost before we
+    /// were done with it: it is retried later, if at all.
+    pub fn failed(&mut self, addr: SocketAddr, now: Instant) {
+        let Some(candidate) = self.candidates.get_mut(&addr) else {
+            return;
+        };
+        candidate.failures += 1;
+        if candidate.failures >= MAX_FAILURES || !candidate.dialable {
+            self.candidates.remove(&addr);
+            return;
+        }
+        candidate.state = State::Idle;
+        candidate.retry_at = now + RETRY_BASE * 2u32.pow(candidate.failures - 1);
+    }
+
+    /// Forgets `addr`, e.g. because it was banned.
+    pub fn forget(&mut self, addr: SocketAddr) {
+        self.candidates.remove(&addr);
+    }
+
+    /// The number of peers we are connected or connecting to.
+    pub fn connections(&self) -> usize {
+        self.candidates
+            .values()
+            .filter(|candidate| candidate.state != State::Idle)
+            .count()
+    }
+
+    /// Whether there are too few peers to reach the target, even counting the ones we can't
+    /// retry yet, so it's time to look for more.
+    pub fn needs_peers(&self) -> bool {
+        self.candidates.len() < self.target
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+}
+
+#[test]
+fn pool_connects_up_to_target_and_backs_off() {
+    let addr = |port| SocketAddr::new([127, 0, 0, 1].into(), port);
+    let now = Instant::now();
+    let mut pool = PeerPool::new(2);
+    pool.add([addr(1), addr(2), addr(3)], now);
+    assert!(!pool.needs_peers());
+
+    let first = pool.to_connect(now);
+    assert_eq!(first.len(), 2);
+    assert!(pool.to_connect(now).is_empty());
+
+    // one connects, the other doesn't and has to wait
+    assert!(pool.connected(first[0], now));
+    assert!(!pool.connected(first[0], now));
+    pool.failed(first[1], now);
+    let next = pool.to_connect(now);
+    assert_eq!(next.len(), 1);
+    assert!(!next.contains(&first[1]));
+    pool.failed(next[0], now);
+    assert!(pool.to_connect(now).is_empty());
+    assert_eq!(pool.to_connect(now + RETRY_BASE).len(), 1);
+
+    // a peer that keeps failing is forgotten
+    let later = now + RETRY_BASE * 2u32.pow(MAX_FAILURES);
+    let mut pool = PeerPool::new(1);
+    pool.add([addr(1)], now);
+    for _ in 0..MAX_FAILURES {
+        assert_eq!(pool.to_connect(later), vec![addr(1)]);
+        pool.failed(addr(1), now);
+    }
+    assert!(pool.is_empty());
+    assert!(pool.needs_peers());
+
+    // a peer that connected to us from some port isn't dialed there once it goes away
+    assert!(pool.accepted(addr(50000), now));
+    assert!(!pool.accepted(addr(50000), now));
+    pool.failed(addr(50000), now);
+    assert!(pool.is_empty());
+    // but a peer we knew of already is
+    pool.add([addr(1)], now);
+    assert!(pool.accepted(addr(1), now));
+    pool.failed(addr(1), now);
+    assert_eq!(pool.to_connect(later), vec![addr(1)]);
+
+    // a peer we keep getting through to is retried soon after every disconnect
+    let mut pool = PeerPool::new(1);
+    pool.add([addr(2)], now);
+    let mut at = now;
+    for _ in 0..MAX_FAILURES * 2 {
+        assert_eq!(pool.to_connect(at), vec![addr(2)]);
+        assert!(pool.connected(addr(2), at));
+        pool.failed(addr(2), at);
+        assert!(pool.to_connect(at).is_empty());
+        at += RETRY_BASE;
+    }
+    assert_eq!(pool.to_connect(at), vec![addr(2)]);
+}
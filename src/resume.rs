@@ -0,0 +1,295 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+
+use crate::{peer::Bitfield, storage::Storage, torrent::Torrent};
+
+/// How many newly verified pieces are written to the record at once.
+const FLUSH_PIECES: usize = 64;
+
+/// How long a newly verified piece may wait to be written to the record.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Fast-resume state for a download, persisted next to the output.
+///
+/// Records which pieces have been verified and written, together with the size and modification
+/// time of every file at that point. When a download restarts, pieces in files that are untouched
+/// since the record was written are trusted as-is, and only pieces in files that changed are
+/// hashed again.
+///
+/// Pieces are recorded in batches rather than one by one, so [`Resume::flush`] must be called
+/// once the download stops; pieces that were never flushed are simply downloaded again.
+pub struct Resume {
+    path: PathBuf,
+    info_hash: [u8; 20],
+    have: Bitfield,
+    /// The size and modification time of every file as last recorded, or `None` for files
+    /// written to since.
+    files: Vec<Option<ResumeFile>>,
+    /// Pieces marked since the record was last written, and when the first of them was.
+    unsaved: usize,
+    unsaved_since: Instant,
+}
+
+/// The on-disk (bencoded) form of [`Resume`].
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ResumeRecord {
+    #[serde(rename = "info hash")]
+    info_hash: ByteBuf,
+
+    /// Bitfield of verified pieces, in the same format as the peer `Bitfield` message.
+    pieces: ByteBuf,
+
+    files: Vec<ResumeFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct ResumeFile {
+    length: u64,
+
+    /// Modification time in nanoseconds since the UNIX epoch.
+    mtime: u64,
+}
+
+impl Resume {
+    /// Where the resume record for `storage` lives: alongside the file or directory it describes.
+    pub fn path_for(storage: &Storage) -> PathBuf {
+        let mut path = storage.root().as_os_str().to_owned();
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+
+    /// Loads the resume record for `t` and checks it against what is currently in `storage`.
+    ///
+    /// A missing record, or one for a different torrent, means starting from scratch. Pieces that
+    /// overlap a file whose size or modification time no longer matches the record are re-hashed,
+    /// and forgotten if they no longer check out.
+    pub async fn load(t: &Torrent, storage: &Storage) -> anyhow::Result<Self> {
+        let path = Self::path_for(storage);
+        let info_hash = t.info_hash();
+        let npieces = t.info.pieces.0.len();
+        let mut resume = Self {
+            path,
+            info_hash,
+            have: Bitfield::new(npieces),
+            files: vec![None; storage.files().len()],
+            unsaved: 0,
+            unsaved_since: Instant::now(),
+        };
+
+        let record = match tokio::fs::read(&resume.path).await {
+            Ok(bytes) => match serde_bencode::from_bytes::<ResumeRecord>(&bytes) {
+                Ok(record) => record,
+                Err(e) => {
+                    eprintln!(
+                        "ignoring corrupt resume file {}: {e}",
+                        resume.path.display()
+                    );
+                    return Ok(resume);
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(resume),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("read resume file {}", resume.path.display()))
+            }
+        };
+        if record.info_hash[..] != info_hash[..] || record.files.len() != storage.files().len() {
+            eprintln!(
+                "resume file {} is for a different torrent",
+                resume.path.display()
+            );
+            return Ok(resume);
+        }
+
+        let Ok(recorded) = Bitfield::from_payload(record.pieces.into_vec(), npieces) else {
+            eprintln!("resume file {} is damaged", resume.path.display());
+            return Ok(resume);
+        };
+        for piece_i in recorded.pieces() {
+            resume.have.set_piece(piece_i);
+        }
+
+        let current = stat_files(storage).await?;
+        let mut recheck = Vec::new();
+        for (file_i, (now, then)) in current.iter().zip(&record.files).enumerate() {
+            if now != then {
+                recheck.extend(storage.file_pieces(file_i));
+            }
+        }
+        recheck.sort_unstable();
+        recheck.dedup();
+
+        for piece_i in recheck {
+            if !resume.have.has_piece(piece_i) {
+                continue;
+            }
+            let data = storage.read_piece(piece_i).await?;
+            let mut hasher = Sha1::new();
+            hasher.update(&data);
+            let hash: [u8; 20] = hasher.finalize().into();
+            if hash != t.info.pieces.0[piece_i] {
+                resume.have.clear_piece(piece_i);
+            }
+        }
+
+        // files may have changed, so make sure the record matches what we just checked
+        resume.files = current.into_iter().map(Some).collect();
+        resume.save(storage).await?;
+        Ok(resume)
+    }
+
+    pub fn has_piece(&self, piece_i: usize) -> bool {
+        self.have.has_piece(piece_i)
+    }
+
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.have
+    }
+
+    /// Records that piece `piece_i` has been verified and written to `storage`. The record itself
+    /// is only written once enough pieces are waiting.
+    pub async fn mark_piece(&mut self, storage: &Storage, piece_i: usize) -> anyhow::Result<()> {
+        self.have.set_piece(piece_i);
+        for (file_i, file) in self.files.iter_mut().enumerate() {
+            if storage.file_pieces(file_i).contains(&piece_i) {
+                *file = None;
+            }
+        }
+        if self.unsaved == 0 {
+            self.unsaved_since = Instant::now();
+        }
+        self.unsaved += 1;
+        if self.unsaved >= FLUSH_PIECES {
+            self.save(storage).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes out the pieces marked since the record was last written, if they have waited long
+    /// enough.
+    pub async fn flush_if_due(&mut self, storage: &Storage) -> anyhow::Result<()> {
+        if self.unsaved > 0 && self.unsaved_since.elapsed() >= FLUSH_INTERVAL {
+            self.save(storage).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes out the pieces marked since the record was last written, if there are any.
+    pub async fn flush(&mut self, storage: &Storage) -> anyhow::Result<()> {
+        if self.unsaved > 0 {
+            self.save(storage).await?;
+        }
+        Ok(())
+    }
+
+    async fn save(&mut self, storage: &Storage) -> anyhow::Result<()> {
+        // only files that pieces were written to can have changed
+        let mut files = Vec::with_capacity(self.files.len());
+        for (cached, file) in self.files.iter_mut().zip(storage.files()) {
+            let stat = match cached {
+                Some(stat) => stat.clone(),
+                None => cached.insert(stat_file(&file.path).await?).clone(),
+            };
+            files.push(stat);
+        }
+        let record = ResumeRecord {
+            info_hash: ByteBuf::from(self.info_hash.to_vec()),
+            pieces: ByteBuf::from(self.have.as_bytes().to_vec()),
+            files,
+        };
+        let bytes = serde_bencode::to_bytes(&record).context("encode resume record")?;
+
+        // write then rename so that a crash never leaves a half-written record behind
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, bytes)
+            .await
+            .context("write resume file")?;
+        tokio::fs::rename(&tmp, &self.path)
+            .await
+            .context("replace resume file")?;
+        self.unsaved = 0;
+        Ok(())
+    }
+}
+
+async fn stat_files(storage: &Storage) -> anyhow::Result<Vec<ResumeFile>> {
+    let mut files = Vec::with_capacity(storage.files().len());
+    for file in storage.files() {
+        files.push(stat_file(&file.path).await?);
+    }
+    Ok(files)
+}
+
+async fn stat_file(path: &Path) -> anyhow::Result<ResumeFile> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("stat {}", path.display()))?;
+    let mtime = metadata
+        .modified()
+        .context("file modification time")?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    Ok(ResumeFile {
+        length: metadata.len(),
+        mtime,
+    })
+}
+
+#[tokio::test]
+async fn resume_rechecks_modified_files() {
+    use crate::storage::{test_files, test_torrent};
+
+    let hash = |data: &[u8]| -> [u8; 20] { Sha1::digest(data).into() };
+    let t = test_torrent(
+        "dir",
+        vec![hash(b"abcd"), hash(b"efgh")],
+        test_files(&[(4, &["a"]), (4, &["b"])]),
+    );
+    let dir = tempfile::tempdir().unwrap();
+
+    let storage = Storage::open(&t, dir.path()).await.unwrap();
+    let mut resume = Resume::load(&t, &storage).await.unwrap();
+    assert!(!resume.has_piece(0) && !resume.has_piece(1));
+    storage.write_piece(0, b"abcd").await.unwrap();
+    resume.mark_piece(&storage, 0).await.unwrap();
+    storage.write_piece(1, b"efgh").await.unwrap();
+    resume.mark_piece(&storage, 1).await.unwrap();
+    // pieces are recorded in batches, so nothing has been written yet
+    assert!(!Resume::path_for(&storage).exists());
+    resume.flush(&storage).await.unwrap();
+    drop(resume);
+
+    let resume = Resume::load(&t, &storage).await.unwrap();
+    assert!(resume.has_piece(0) && resume.has_piece(1));
+    drop(resume);
+
+    // reopening the storage, as a restarted download does, leaves the files untouched: a piece
+    // that went bad without its file's modification time changing is still trusted
+    let b = dir.path().join("dir").join("b");
+    let mtime = std::fs::metadata(&b).unwrap().modified().unwrap();
+    std::fs::write(&b, b"efgY").unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&b)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    let storage = Storage::open(&t, dir.path()).await.unwrap();
+    let resume = Resume::load(&t, &storage).await.unwrap();
+    assert!(resume.has_piece(0) && resume.has_piece(1));
+    drop(resume);
+
+    // corrupt the second file behind the record's back
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    std::fs::write(dir.path().join("dir").join("b"), b"efgX").unwrap();
+    let resume = Resume::load(&t, &storage).await.unwrap();
+    assert!(resume.has_piece(0));
+    assert!(!resume.has_piece(1));
+}
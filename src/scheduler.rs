@@ -0,0 +1,456 @@
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use tokio::sync::{broadcast, Notify};
+
+use crate::peer::Bitfield;
+use crate::picker::Picker;
+use crate::swarm::SwarmConfig;
+use crate::BLOCK_MAX;
+
+/// Hands out the blocks of the pieces we still need to the peers downloading them, so that every
+/// peer can work on pieces of its own at the same time.
+///
+/// Blocks of partially downloaded pieces are handed out first. New pieces are started as the
+/// [`Picker`] sees fit, but only while fewer than a configured number of pieces are partially
+/// downloaded, so that pieces get finished (and can be served on) rather than all being started
+/// at once. A peer that has none of the partial pieces waits for one to finish. Partial pieces
+/// that no connected peer has don't count, as nobody could finish them.
+///
+/// Once every remaining block has been requested, the download enters endgame: rather than
+/// waiting on whichever peers were handed the last blocks, blocks are requested again from other
+/// peers that have them, and whoever else was asked is told to cancel once one copy arrives.
+pub(crate) struct Scheduler {
+    state: Mutex<State>,
+    max_partial: usize,
+    /// Woken whenever blocks become available to request, or the last piece is done.
+    work: Notify,
+    /// Blocks that arrived while other peers were asked for them too.
+    cancels: broadcast::Sender<(usize, usize)>,
+}
+
+struct State {
+    picker: Picker,
+    lengths: Vec<usize>,
+    /// Pieces with blocks requested or received, oldest first.
+    partial: Vec<Partial>,
+    endgame: EndgameStats,
+}
+
+/// What endgame cost us.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct EndgameStats {
+    /// Requests for blocks that had already been requested from another peer.
+    pub(crate) duplicate_requests: u64,
+    /// Bytes of blocks that arrived after another copy already had.
+    pub(crate) wasted_bytes: u64,
+}
+
+/// A piece that has blocks requested or received.
+struct Partial {
+    piece_i: usize,
+    length: usize,
+    blocks: Vec<BlockState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Open,
+    /// Requested from these peers; more than one only in endgame.
+    Requested(Vec<SocketAddr>),
+    Received,
+}
+
+/// A block to request from a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlockRequest {
+    pub(crate) piece_i: usize,
+    pub(crate) begin: usize,
+    pub(crate) length: usize,
+}
+
+/// A block a peer delivered, on its way to be assembled into its piece.
+#[derive(Debug)]
+pub(crate) struct Block {
+    pub(crate) piece_i: usize,
+    pub(crate) begin: usize,
+    pub(crate) data: Vec<u8>,
+    pub(crate) peer: SocketAddr,
+}
+
+impl Scheduler {
+    /// Schedules the pieces we don't `have`, where piece `i` is `lengths[i]` bytes long.
+    pub(crate) fn new(lengths: Vec<usize>, have: &Bitfield, config: &SwarmConfig) -> Self {
+        Self {
+            state: Mutex::new(State {
+                picker: Picker::new(have, lengths.len(), config.random_first_pieces),
+                lengths,
+                partial: Vec::new(),
+                endgame: EndgameStats::default(),
+            }),
+            max_partial: config.max_partial_pieces.max(1),
+            work: Notify::new(),
+            cancels: broadcast::channel(64).0,
+        }
+    }
+
+    /// Counts the pieces of a peer that connected.
+    pub(crate) fn add_peer(&self, has: &Bitfield) {
+        self.state
+            .lock()
+            .expect("not poisoned")
+            .picker
+            .add_peer(has);
+    }
+
+    /// Counts a piece that a connected peer just got; it may be one that peers waiting for work
+    /// can start on.
+    pub(crate) fn peer_has(&self, piece_i: usize) {
+        self.state
+            .lock()
+            .expect("not poisoned")
+            .picker
+            .peer_has(piece_i);
+    }
+
+    /// Picks the next block for the peer at `addr`, which has the pieces in `has`.
+    ///
+    /// Blocks of pieces that are already partially downloaded come first; a new piece is only
+    /// started if there is room for one. In endgame, the block asked of the fewest other peers
+    /// is picked.
+    pub(crate) fn next_block(&self, addr: SocketAddr, has: &Bitfield) -> Option<BlockRequest> {
+        let mut state = self.state.lock().expect("not poisoned");
+        let found = match state.open_block(has) {
+            Some(found) => Some(found),
+            None => state.start_piece(has, self.max_partial),
+        };
+        let (partial_i, block_i) = match found {
+            Some(found) => found,
+            None => {
+                let duplicate = state.endgame_block(addr, has)?;
+                state.endgame.duplicate_requests += 1;
+                duplicate
+            }
+        };
+
+        let partial = &mut state.partial[partial_i];
+        match &mut partial.blocks[block_i] {
+            BlockState::Requested(peers) => peers.push(addr),
+            block => *block = BlockState::Requested(vec![addr]),
+        }
+        let begin = block_i * BLOCK_MAX;
+        Some(BlockRequest {
+            piece_i: partial.piece_i,
+            begin,
+            length: BLOCK_MAX.min(partial.length - begin),
+        })
+    }
+
+    /// Records that the `length` bytes at `begin` in piece `piece_i` arrived. Returns `false` if
+    /// they were not asked for or have already arrived, in which case they should be thrown away.
+    pub(crate) fn received(&self, piece_i: usize, begin: usize, length: usize) -> bool {
+        let mut state = self.state.lock().expect("not poisoned");
+        let block = state
+            .partial
+            .iter_mut()
+            .find(|partial| partial.piece_i == piece_i)
+            .and_then(|partial| partial.blocks.get_mut(begin / BLOCK_MAX));
+        let previous = match block {
+            Some(block @ BlockState::Requested(_)) => {
+                std::mem::replace(block, BlockState::Received)
+            }
+            // a copy already arrived, and the piece may even be done
+            Some(BlockState::Received) | None => {
+                state.endgame.wasted_bytes += length as u64;
+                return false;
+            }
+            // left over from an earlier attempt at the block, which has been given up on since
+            Some(BlockState::Open) => return false,
+        };
+        if matches!(previous, BlockState::Requested(peers) if peers.len() > 1) {
+            // nobody may be in endgame any more, which is fine
+            let _ = self.cancels.send((piece_i, begin));
+        }
+        true
+    }
+
+    /// Makes a block that was requested from the peer at `addr` but will not arrive (e.g. because
+    /// the peer choked us) available to be requested again.
+    pub(crate) fn returned(&self, addr: SocketAddr, piece_i: usize, begin: usize) {
+        let mut state = self.state.lock().expect("not poisoned");
+        if let Some(block) = state
+            .partial
+            .iter_mut()
+            .find(|partial| partial.piece_i == piece_i)
+            .and_then(|partial| partial.blocks.get_mut(begin / BLOCK_MAX))
+        {
+            block.forget(addr);
+        }
+        drop(state);
+        self.work.notify_waiters();
+    }
+
+    /// Forgets the peer at `addr`, which had the pieces in `has`, and makes every block still
+    /// requested from it available again.
+    pub(crate) fn remove_peer(&self, addr: SocketAddr, has: &Bitfield) {
+        let mut state = self.state.lock().expect("not poisoned");
+        state.picker.remove_peer(has);
+        for partial in &mut state.partial {
+            for block in &mut partial.blocks {
+                block.forget(addr);
+            }
+        }
+        drop(state);
+        self.work.notify_waiters();
+    }
+
+    /// Records that piece `piece_i` is verified and stored, making room for another.
+    pub(crate) fn finished(&self, piece_i: usize) {
+        let mut state = self.state.lock().expect("not poisoned");
+        state.partial.retain(|partial| partial.piece_i != piece_i);
+        state.picker.finished();
+        drop(state);
+        self.work.notify_waiters();
+    }
+
+    /// Records that piece `piece_i` failed its hash check, so it has to be downloaded again.
+    pub(crate) fn failed(&self, piece_i: usize) {
+        let mut state = self.state.lock().expect("not poisoned");
+        state.partial.retain(|partial| partial.piece_i != piece_i);
+        state.picker.unpick(piece_i);
+        drop(state);
+        self.work.notify_waiters();
+    }
+
+    /// Whether every piece has been downloaded and verified.
+    pub(crate) fn is_done(&self) -> bool {
+        let state = self.state.lock().expect("not poisoned");
+        !state.picker.any_wanted() && state.partial.is_empty()
+    }
+
+    /// Resolves once there may be new blocks to request.
+    ///
+    /// Enable the returned future before looking for blocks, so that a wakeup in between is not
+    /// lost.
+    pub(crate) fn notified(&self) -> tokio::sync::futures::Notified<'_> {
+        self.work.notified()
+    }
+
+    /// Announces every block, as `(piece_i, begin)`, that arrived while other peers were asked
+    /// for it too, so they can be told not to bother.
+    pub(crate) fn cancels(&self) -> broadcast::Receiver<(usize, usize)> {
+        self.cancels.subscribe()
+    }
+
+    pub(crate) fn endgame_stats(&self) -> EndgameStats {
+        self.state.lock().expect("not poisoned").endgame
+    }
+}
+
+impl State {
+    /// The first block nobody has been asked for, in the oldest partial piece in `has`, as
+    /// `(partial_i, block_i)`.
+    fn open_block(&self, has: &Bitfield) -> Option<(usize, usize)> {
+        self.partial
+            .iter()
+            .enumerate()
+            .find_map(|(partial_i, partial)| {
+                if !has.has_piece(partial.piece_i) {
+                    return None;
+                }
+                let block_i = partial.blocks.iter().position(|b| *b == BlockState::Open)?;
+                Some((partial_i, block_i))
+            })
+    }
+
+    /// Starts on a new piece in `has`, if fewer than `max_partial` that a connected peer has are
+    /// in progress.
+    fn start_piece(&mut self, has: &Bitfield, max_partial: usize) -> Option<(usize, usize)> {
+        // the peers that had the others have left, so waiting on them could take forever
+        let in_progress = self
+            .partial
+            .iter()
+            .filter(|partial| self.picker.availability(partial.piece_i) > 0)
+            .count();
+        if in_progress >= max_partial {
+            return None;
+        }
+        let piece_i = self.picker.pick(has)?;
+        let length = self.lengths[piece_i];
+        self.partial.push(Partial {
+            piece_i,
+            length,
+            blocks: vec![BlockState::Open; length.div_ceil(BLOCK_MAX)],
+        });
+        Some((self.partial.len() - 1, 0))
+    }
+
+    /// In endgame, the block in `has` that the peer at `addr` hasn't been asked for and the fewest
+    /// other peers have.
+    fn endgame_block(&self, addr: SocketAddr, has: &Bitfield) -> Option<(usize, usize)> {
+        let open = self
+            .partial
+            .iter()
+            .any(|partial| partial.blocks.contains(&BlockState::Open));
+        if open || self.picker.any_wanted() {
+            // some blocks haven't been asked for at all yet
+            return None;
+        }
+        self.partial
+            .iter()
+            .enumerate()
+            .filter(|(_, partial)| has.has_piece(partial.piece_i))
+            .flat_map(|(partial_i, partial)| {
+                partial
+                    .blocks
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(block_i, block)| match block {
+                        BlockState::Requested(peers) if !peers.contains(&addr) => {
+                            Some((peers.len(), partial_i, block_i))
+                        }
+                        _ => None,
+                    })
+            })
+            .min()
+            .map(|(_, partial_i, block_i)| (partial_i, block_i))
+    }
+}
+
+impl BlockState {
+    /// Forgets that the block was requested from the peer at `addr`, reopening it if nobody else
+    /// was asked.
+    fn forget(&mut self, addr: SocketAddr) {
+        if let BlockState::Requested(peers) = self {
+            peers.retain(|&peer| peer != addr);
+            if peers.is_empty() {
+                *self = BlockState::Open;
+            }
+        }
+    }
+}
+
+#[test]
+fn scheduler_spreads_peers_over_pieces() {
+    let a = SocketAddr::new([127, 0, 0, 1].into(), 1);
+    let b = SocketAddr::new([127, 0, 0, 1].into(), 2);
+    let mut all = Bitfield::new(3);
+    (0..3).for_each(|piece_i| all.set_piece(piece_i));
+    let mut only_last = Bitfield::new(3);
+    only_last.set_piece(2);
+
+    // pieces of one and a half blocks, at most two in flight
+    let config = SwarmConfig {
+        max_partial_pieces: 2,
+        random_first_pieces: 0,
+        ..SwarmConfig::default()
+    };
+    let scheduler = Scheduler::new(vec![BLOCK_MAX * 3 / 2; 3], &Bitfield::new(3), &config);
+    scheduler.add_peer(&all);
+    scheduler.add_peer(&only_last);
+    scheduler.peer_has(1);
+    // 0 is the rarest
+    let first = scheduler.next_block(a, &all).unwrap();
+    assert_eq!(
+        (first.piece_i, first.begin, first.length),
+        (0, 0, BLOCK_MAX)
+    );
+    // b doesn't have piece 0, so it starts the one it has
+    let other = scheduler.next_block(b, &only_last).unwrap();
+    assert_eq!(other.piece_i, 2);
+    let second = scheduler.next_block(a, &all).unwrap();
+    assert_eq!(
+        (second.piece_i, second.begin, second.length),
+        (0, BLOCK_MAX, BLOCK_MAX / 2)
+    );
+    // partial pieces come first, and piece 1 can't start while two are in flight
+    assert_eq!(scheduler.next_block(a, &all).unwrap().piece_i, 2);
+    assert!(scheduler.next_block(a, &all).is_none());
+
+    // b goes away before delivering, so its block is up for grabs again
+    scheduler.remove_peer(b, &only_last);
+    let retry = scheduler.next_block(a, &all).unwrap();
+    assert_eq!((retry.piece_i, retry.begin), (2, 0));
+
+    // a gives the block back too, so a late copy from b's attempt is no longer wanted
+    scheduler.returned(a, 2, 0);
+    assert!(!scheduler.received(2, 0, BLOCK_MAX));
+    assert_eq!(scheduler.next_block(a, &all).unwrap(), retry);
+
+    assert!(scheduler.received(0, 0, BLOCK_MAX));
+    assert!(!scheduler.received(0, 0, BLOCK_MAX));
+    assert!(!scheduler.received(1, 0, BLOCK_MAX));
+    assert!(scheduler.received(0, BLOCK_MAX, BLOCK_MAX / 2));
+    scheduler.finished(0);
+    assert_eq!(scheduler.next_block(a, &all).unwrap().piece_i, 1);
+    assert!(!scheduler.is_done());
+}
+
+#[test]
+fn scheduler_starts_pieces_when_partial_ones_are_stranded() {
+    let a = SocketAddr::new([127, 0, 0, 1].into(), 1);
+    let b = SocketAddr::new([127, 0, 0, 1].into(), 2);
+    let mut first = Bitfield::new(2);
+    first.set_piece(0);
+    let mut second = Bitfield::new(2);
+    second.set_piece(1);
+
+    let config = SwarmConfig {
+        max_partial_pieces: 1,
+        random_first_pieces: 0,
+        ..SwarmConfig::default()
+    };
+    let scheduler = Scheduler::new(vec![BLOCK_MAX * 2; 2], &Bitfield::new(2), &config);
+    scheduler.add_peer(&first);
+    scheduler.add_peer(&second);
+    assert_eq!(scheduler.next_block(a, &first).unwrap().piece_i, 0);
+    // piece 0 is in progress, so b has to wait
+    assert!(scheduler.next_block(b, &second).is_none());
+
+    // a leaves, and nobody else can finish piece 0
+    scheduler.remove_peer(a, &first);
+    assert_eq!(scheduler.next_block(b, &second).unwrap().piece_i, 1);
+    assert!(scheduler.next_block(b, &second).is_some());
+    assert!(scheduler.next_block(b, &second).is_none());
+
+    // once someone with piece 0 turns up again it is picked up where it was left
+    scheduler.add_peer(&first);
+    let resumed = scheduler.next_block(a, &first).unwrap();
+    assert_eq!((resumed.piece_i, resumed.begin), (0, 0));
+}
+
+#[test]
+fn scheduler_endgame_duplicates_and_cancels() {
+    let a = SocketAddr::new([127, 0, 0, 1].into(), 1);
+    let b = SocketAddr::new([127, 0, 0, 1].into(), 2);
+    let c = SocketAddr::new([127, 0, 0, 1].into(), 3);
+    let mut all = Bitfield::new(1);
+    all.set_piece(0);
+
+    let scheduler = Scheduler::new(
+        vec![BLOCK_MAX * 2],
+        &Bitfield::new(1),
+        &SwarmConfig::default(),
+    );
+    let mut cancels = scheduler.cancels();
+    let first = scheduler.next_block(a, &all).unwrap();
+    assert_eq!(scheduler.next_block(b, &all).unwrap().begin, BLOCK_MAX);
+    // everything is requested: c is asked for the first block too, then b (which already has
+    // the second) for the first, and a for the second
+    assert_eq!(scheduler.next_block(c, &all).unwrap(), first);
+    assert_eq!(scheduler.next_block(b, &all).unwrap(), first);
+    assert_eq!(scheduler.next_block(a, &all).unwrap().begin, BLOCK_MAX);
+    assert!(scheduler.next_block(a, &all).is_none());
+
+    // c delivers first, so a and b should cancel; a's copy arrives anyway
+    assert!(scheduler.received(0, 0, BLOCK_MAX));
+    assert_eq!(cancels.try_recv().unwrap(), (0, 0));
+    assert!(!scheduler.received(0, 0, BLOCK_MAX));
+    // b chokes us, but a was asked for the second block too, so it isn't reopened
+    scheduler.returned(b, 0, BLOCK_MAX);
+    assert!(scheduler.next_block(c, &all).is_some());
+
+    let stats = scheduler.endgame_stats();
+    assert_eq!(stats.duplicate_requests, 4);
+    assert_eq!(stats.wasted_bytes, BLOCK_MAX as u64);
+}
@@ -0,0 +1,117 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::{
+    choker,
+    dht::Dht,
+    download::find_peers,
+    listener::{Listener, DEFAULT_PORT},
+    lsd::Lsd,
+    peer::Peer,
+    swarm::Swarm,
+    torrent::Torrent,
+    tracker::TrackerTiers,
+};
+
+/// How often to announce again to find new peers to serve, unless the trackers say otherwise.
+const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Serves the pieces `swarm` has to every peer the trackers (and, unless the torrent is private,
+/// `dht` and `lsd`) know of, announcing again every so often to find new ones, and to every peer
+/// that connects through `listener`. Runs until cancelled, or until Ctrl-C, after which the
+/// trackers are told that we are leaving.
+pub async fn seed(
+    t: &Torrent,
+    swarm: &Arc<Swarm>,
+    dht: Option<&Dht>,
+    listener: Option<&Listener>,
+    lsd: Option<&Lsd>,
+) -> anyhow::Result<()> {
+    let dht = dht.filter(|_| !t.is_private());
+    let mut incoming = listener.map(|listener| listener.register(Arc::clone(swarm)));
+    let _choker = choker::start(Arc::clone(swarm));
+    let port = listener.map_or(DEFAULT_PORT, Listener::port);
+    let mut local = lsd
+        .filter(|_| !t.is_private())
+        .map(|lsd| lsd.register(swarm.info_hash, port));
+    let mut trackers = TrackerTiers::new(t.trackers());
+    let mut connected = HashSet::new();
+    let mut peers = JoinSet::new();
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        match find_peers(&mut trackers, dht, swarm.info_hash, swarm.progress(), port).await {
+            Ok(found) => {
+                for peer_addr in found {
+                    if connected.insert(peer_addr) {
+                        connect(&mut peers, swarm, peer_addr);
+                    }
+                }
+            }
+            Err(e) => eprintln!("{e:#}"),
+        }
+
+        let reannounce = tokio::time::sleep(trackers.interval().unwrap_or(REANNOUNCE_INTERVAL));
+        tokio::pin!(reannounce);
+        loop {
+            tokio::select! {
+                _ = &mut ctrl_c => {
+                    trackers.stopped(swarm.info_hash, swarm.progress(), port).await;
+                    return Ok(());
+                }
+                _ = &mut reannounce => break,
+                Some(disconnected) = peers.join_next() => {
+                    if let Ok(peer_addr) = disconnected {
+                        connected.remove(&peer_addr);
+                    }
+                }
+                Some(peer_addr) = recv(&mut local) => {
+                    if connected.insert(peer_addr) {
+                        connect(&mut peers, swarm, peer_addr);
+                    }
+                }
+                Some(peer) = recv(&mut incoming) => {
+                    let peer_addr = peer.addr;
+                    if connected.insert(peer_addr) {
+                        peers.spawn(async move {
+                            serve(peer).await;
+                            peer_addr
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Connects to the peer at `peer_addr` and serves it in the background.
+fn connect(peers: &mut JoinSet<SocketAddr>, swarm: &Arc<Swarm>, peer_addr: SocketAddr) {
+    let swarm = Arc::clone(swarm);
+    peers.spawn(async move {
+        match Peer::new(peer_addr, swarm).await {
+            Ok(peer) => serve(peer).await,
+            Err(e) => eprintln!("failed to connect to peer {peer_addr}: {e:#}"),
+        }
+        peer_addr
+    });
+}
+
+/// Serves `peer` until either side disconnects.
+async fn serve(mut peer: Peer) {
+    if let Err(e) = peer.seed().await {
+        eprintln!("peer {} failed: {e:#}", peer.addr);
+    }
+}
+
+/// The next peer from `incoming`, if there is a channel to receive from.
+async fn recv<T>(incoming: &mut Option<mpsc::Receiver<T>>) -> Option<T> {
+    match incoming {
+        Some(incoming) => incoming.recv().await,
+        None => None,
+    }
+}
@@ -0,0 +1,115 @@
+//! Working out which peers sent bad data, from pieces that failed to verify.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use sha1::{Digest, Sha1};
+
+use crate::BLOCK_MAX;
+
+/// Keeps track of who contributed to pieces that failed their hash check.
+///
+/// Every peer that sent a block of a failed piece gets a strike, and a peer with too many strikes
+/// is banned. That alone would also punish the honest peers that happened to share a piece with a
+/// bad one, so the hash of every block of a failed piece is remembered too: once the piece does
+/// verify, a peer whose block differs from the good data is banned outright, and the others get
+/// their strike back.
+#[derive(Debug, Clone, Default)]
+pub struct SmartBan {
+    /// The blocks of failed pieces that have not verified since: `(peer, block_i, hash)`.
+    suspects: HashMap<usize, Vec<(SocketAddr, usize, [u8; 20])>>,
+    strikes: HashMap<IpAddr, usize>,
+    max_strikes: usize,
+}
+
+impl SmartBan {
+    /// Bans peers once they contributed to `max_strikes` failed pieces.
+    pub fn new(max_strikes: usize) -> Self {
+        Self {
+            max_strikes: max_strikes.max(1),
+            ..Default::default()
+        }
+    }
+
+    /// Records that piece `piece_i` failed to verify, where block `i` of `data` came from
+    /// `contributors[i]`. Returns the IPs to ban for it.
+    pub fn failed(
+        &mut self,
+        piece_i: usize,
+        data: &[u8],
+        contributors: &[SocketAddr],
+    ) -> Vec<IpAddr> {
+        let suspects = self.suspects.entry(piece_i).or_default();
+        let mut banned = Vec::new();
+        let mut struck = Vec::new();
+        for (block_i, (block, &peer)) in data.chunks(BLOCK_MAX).zip(contributors).enumerate() {
+            suspects.push((peer, block_i, hash(block)));
+            if struck.contains(&peer.ip()) {
+                continue;
+            }
+            struck.push(peer.ip());
+            let strikes = self.strikes.entry(peer.ip()).or_default();
+            *strikes += 1;
+            if *strikes >= self.max_strikes {
+                banned.push(peer.ip());
+            }
+        }
+        banned
+    }
+
+    /// Records that piece `piece_i` verified as `data`, and returns the IPs of the peers that
+    /// sent different data for it before.
+    pub fn verified(&mut self, piece_i: usize, data: &[u8]) -> Vec<IpAddr> {
+        let Some(suspects) = self.suspects.remove(&piece_i) else {
+            return Vec::new();
+        };
+        let good: Vec<[u8; 20]> = data.chunks(BLOCK_MAX).map(hash).collect();
+        let mut banned: Vec<IpAddr> = Vec::new();
+        for (peer, block_i, hash) in &suspects {
+            if good.get(*block_i) != Some(hash) && !banned.contains(&peer.ip()) {
+                banned.push(peer.ip());
+            }
+        }
+
+        let mut cleared: Vec<IpAddr> = Vec::new();
+        for (peer, _, _) in suspects {
+            if banned.contains(&peer.ip()) || cleared.contains(&peer.ip()) {
+                continue;
+            }
+            // each failed piece gave the peer one strike, however many of its blocks it sent
+            cleared.push(peer.ip());
+            if let Some(strikes) = self.strikes.get_mut(&peer.ip()) {
+                *strikes = strikes.saturating_sub(1);
+            }
+        }
+        banned
+    }
+}
+
+fn hash(block: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(block);
+    hasher.finalize().into()
+}
+
+#[test]
+fn smartban_finds_the_peer_that_sent_bad_data() {
+    let honest = SocketAddr::new([10, 0, 0, 1].into(), 1);
+    let liar = SocketAddr::new([10, 0, 0, 2].into(), 1);
+    let good = vec![7u8; BLOCK_MAX * 2];
+    let mut bad = good.clone();
+    bad[BLOCK_MAX] = 0;
+
+    let mut smartban = SmartBan::new(2);
+    assert!(smartban.failed(0, &bad, &[honest, liar]).is_empty());
+    // the honest peer gets its strike back when the piece verifies, the liar is found out
+    assert_eq!(smartban.verified(0, &good), vec![liar.ip()]);
+    assert!(smartban.verified(0, &good).is_empty());
+    assert!(smartban.failed(1, &bad, &[honest, honest]).is_empty());
+
+    // without a good copy to compare against, strikes add up
+    assert_eq!(
+        smartban.failed(2, &bad, &[honest, liar]),
+        vec![honest.ip(), liar.ip()]
+    );
+}
@@ -0,0 +1,374 @@
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Context;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::torrent::{Keys, Torrent};
+
+/// Disk-backed storage for the content of a torrent.
+///
+/// Verified pieces are written straight to their final offset in the output file(s), so the
+/// torrent never has to fit in memory. For multi-file torrents the files are laid out back to back
+/// (as the metainfo describes), and a piece that straddles a file boundary is split across them.
+pub struct Storage {
+    root: PathBuf,
+    files: Vec<StorageFile>,
+    length: usize,
+    plength: usize,
+}
+
+/// A file on disk, and where it sits in the concatenated torrent data.
+#[derive(Debug, Clone)]
+pub struct StorageFile {
+    pub path: PathBuf,
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// The part of a [`StorageFile`] that a range of torrent data maps onto.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// Index into [`Storage::files`].
+    pub file_i: usize,
+    /// Offset within the file.
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl Storage {
+    /// Opens (or creates) the output file(s) for `t` and sizes them to their final lengths.
+    ///
+    /// A single-file torrent is stored at `output`. A multi-file torrent is stored in a directory
+    /// tree under `output/<name>`. Existing data is left in place.
+    pub async fn open(t: &Torrent, output: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let storage = Self::existing(t, output)?;
+        for file in &storage.files {
+            if let Some(parent) = file.path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .with_context(|| format!("create directory {}", parent.display()))?;
+            }
+            let f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file.path)
+                .await
+                .with_context(|| format!("open output file {}", file.path.display()))?;
+            let length = f
+                .metadata()
+                .await
+                .with_context(|| format!("stat output file {}", file.path.display()))?
+                .len();
+            // even a no-op resize bumps the modification time, which fast-resume relies on
+            if length != file.length as u64 {
+                f.set_len(file.length as u64)
+                    .await
+                    .with_context(|| format!("size output file {}", file.path.display()))?;
+            }
+        }
+        Ok(storage)
+    }
+
+    /// Describes the data for `t` at `output` (laid out as for [`Storage::open`]) without creating
+    /// or resizing anything on disk.
+    pub fn existing(t: &Torrent, output: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let storage = Self::layout(t, output)?;
+        anyhow::ensure!(
+            t.info.pieces.0.len() == storage.npieces(),
+            "torrent has {} piece hashes for {} pieces",
+            t.info.pieces.0.len(),
+            storage.npieces()
+        );
+        Ok(storage)
+    }
+
+    /// Lays out the data for `t` at `output` like [`Storage::existing`], but without checking
+    /// the piece hashes, which a torrent that is still being created doesn't have yet.
+    pub(crate) fn layout(t: &Torrent, output: impl AsRef<Path>) -> anyhow::Result<Self> {
+        anyhow::ensure!(t.info.plength > 0, "torrent has a piece length of 0");
+        let output = output.as_ref();
+        let mut files = Vec::new();
+        let mut offset = 0;
+        let root = match &t.info.keys {
+            Keys::SingleFile { length } => {
+                files.push(StorageFile {
+                    path: output.to_path_buf(),
+                    offset,
+                    length: *length,
+                });
+                output.to_path_buf()
+            }
+            Keys::MultiFile { files: entries } => {
+                let root = output.join(safe_component(&t.info.name)?);
+                for entry in entries {
+                    anyhow::ensure!(!entry.path.is_empty(), "file with empty path in torrent");
+                    let mut path = root.clone();
+                    for component in &entry.path {
+                        path.push(safe_component(component)?);
+                    }
+                    files.push(StorageFile {
+                        path,
+                        offset,
+                        length: entry.length,
+                    });
+                    offset += entry.length;
+                }
+                root
+            }
+        };
+
+        Ok(Self {
+            root,
+            files,
+            length: t.length(),
+            plength: t.info.plength,
+        })
+    }
+
+    pub fn files(&self) -> &[StorageFile] {
+        &self.files
+    }
+
+    /// The root of the stored data: the file itself, or the directory holding all the files.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn npieces(&self) -> usize {
+        self.length.div_ceil(self.plength)
+    }
+
+    /// The length of piece `piece_i`; only the last piece may be shorter than the piece length.
+    pub fn piece_length(&self, piece_i: usize) -> usize {
+        let offset = piece_i * self.plength;
+        self.plength.min(self.length.saturating_sub(offset))
+    }
+
+    /// The range of pieces that hold (part of) file `file_i`.
+    pub fn file_pieces(&self, file_i: usize) -> std::ops::Range<usize> {
+        let file = &self.files[file_i];
+        if file.length == 0 {
+            return 0..0;
+        }
+        file.offset / self.plength..(file.offset + file.length).div_ceil(self.plength)
+    }
+
+    /// Maps `length` bytes of torrent data starting at `offset` onto the files that hold them.
+    pub fn spans(&self, offset: usize, length: usize) -> impl Iterator<Item = Span> + '_ {
+        let end = offset + length;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(move |(_, file)| {
+                file.length > 0 && file.offset < end && offset < file.offset + file.length
+            })
+            .map(move |(file_i, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                Span {
+                    file_i,
+                    offset: start - file.offset,
+                    length: stop - start,
+                }
+            })
+    }
+
+    /// Writes the (already verified) bytes of piece `piece_i` to disk.
+    pub async fn write_piece(&self, piece_i: usize, data: &[u8]) -> anyhow::Result<()> {
+        let offset = piece_i * self.plength;
+        anyhow::ensure!(
+            offset + data.len() <= self.length,
+            "piece {piece_i} extends past the end of the torrent"
+        );
+
+        let mut data = data;
+        for span in self.spans(offset, data.len()) {
+            let file = &self.files[span.file_i];
+            let (chunk, rest) = data.split_at(span.length);
+            data = rest;
+
+            let mut f = OpenOptions::new()
+                .write(true)
+                .open(&file.path)
+                .await
+                .with_context(|| format!("open output file {}", file.path.display()))?;
+            f.seek(SeekFrom::Start(span.offset as u64))
+                .await
+                .context("seek to piece offset")?;
+            f.write_all(chunk)
+                .await
+                .with_context(|| format!("write piece {piece_i} to {}", file.path.display()))?;
+            f.flush().await.context("flush piece")?;
+        }
+        Ok(())
+    }
+
+    /// Reads the bytes of piece `piece_i` back from disk.
+    pub async fn read_piece(&self, piece_i: usize) -> anyhow::Result<Vec<u8>> {
+        self.read_block(piece_i, 0, self.piece_length(piece_i))
+            .await
+    }
+
+    /// Reads `length` bytes at offset `begin` within piece `piece_i` back from disk.
+    pub async fn read_block(
+        &self,
+        piece_i: usize,
+        begin: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        anyhow::ensure!(length > 0, "cannot read an empty block");
+        anyhow::ensure!(
+            begin + length <= self.piece_length(piece_i),
+            "block extends past the end of piece {piece_i}"
+        );
+        let offset = piece_i * self.plength + begin;
+
+        let mut data = vec![0; length];
+        let mut buf = &mut data[..];
+        for span in self.spans(offset, length) {
+            let file = &self.files[span.file_i];
+            let (chunk, rest) = buf.split_at_mut(span.length);
+            buf = rest;
+
+            let mut f = tokio::fs::File::open(&file.path)
+                .await
+                .with_context(|| format!("open {}", file.path.display()))?;
+            f.seek(SeekFrom::Start(span.offset as u64))
+                .await
+                .context("seek to piece offset")?;
+            f.read_exact(chunk)
+                .await
+                .with_context(|| format!("read piece {piece_i} from {}", file.path.display()))?;
+        }
+        Ok(data)
+    }
+}
+
+/// Rejects path components from the metainfo that would escape the download directory.
+fn safe_component(component: &str) -> anyhow::Result<&str> {
+    let mut components = Path::new(component).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(component),
+        _ => anyhow::bail!("unsafe path component {component:?} in torrent"),
+    }
+}
+
+/// A trackerless torrent called `name` made of 4 byte pieces, for tests.
+#[cfg(test)]
+pub(crate) fn test_torrent(name: &str, pieces: Vec<[u8; 20]>, keys: Keys) -> Torrent {
+    use crate::torrent::{Hashes, Info};
+
+    Torrent {
+        announce: String::new(),
+        announce_list: None,
+        comment: None,
+        created_by: None,
+        creation_date: None,
+        nodes: None,
+        info: Info {
+            name: name.to_string(),
+            plength: 4,
+            pieces: Hashes(pieces),
+            private: None,
+            keys,
+        },
+        raw_info: None,
+    }
+}
+
+/// The keys of a multi-file torrent, from the `(length, path)` of each file.
+#[cfg(test)]
+pub(crate) fn test_files(files: &[(usize, &[&str])]) -> Keys {
+    Keys::MultiFile {
+        files: files
+            .iter()
+            .map(|&(length, path)| crate::torrent::File {
+                length,
+                path: path.iter().map(|s| s.to_string()).collect(),
+            })
+            .collect(),
+    }
+}
+
+#[tokio::test]
+async fn storage_writes_pieces_at_offset() {
+    let t = test_torrent("out", vec![[0; 20]; 3], Keys::SingleFile { length: 10 });
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("out");
+
+    let storage = Storage::open(&t, &path).await.unwrap();
+    storage.write_piece(2, b"ij").await.unwrap();
+    storage.write_piece(0, b"abcd").await.unwrap();
+    assert!(storage.write_piece(2, b"ijk").await.is_err());
+    drop(storage);
+
+    let data = std::fs::read(&path).unwrap();
+    assert_eq!(data, b"abcd\0\0\0\0ij");
+}
+
+#[tokio::test]
+async fn storage_splits_pieces_across_files() {
+    let t = test_torrent(
+        "dir",
+        vec![[0; 20]; 3],
+        test_files(&[
+            (3, &["a"]),
+            (0, &["empty"]),
+            (6, &["sub", "b"]),
+            (1, &["c"]),
+        ]),
+    );
+    let dir = tempfile::tempdir().unwrap();
+
+    let storage = Storage::open(&t, dir.path()).await.unwrap();
+    assert_eq!(
+        storage.spans(0, 4).collect::<Vec<_>>(),
+        vec![
+            Span {
+                file_i: 0,
+                offset: 0,
+                length: 3
+            },
+            Span {
+                file_i: 2,
+                offset: 0,
+                length: 1
+            },
+        ]
+    );
+    storage.write_piece(0, b"abcd").await.unwrap();
+    storage.write_piece(1, b"efgh").await.unwrap();
+    storage.write_piece(2, b"ij").await.unwrap();
+
+    let root = dir.path().join("dir");
+    assert_eq!(std::fs::read(root.join("a")).unwrap(), b"abc");
+    assert_eq!(std::fs::read(root.join("empty")).unwrap(), b"");
+    assert_eq!(
+        std::fs::read(root.join("sub").join("b")).unwrap(),
+        b"defghi"
+    );
+    assert_eq!(std::fs::read(root.join("c")).unwrap(), b"j");
+}
+
+#[tokio::test]
+async fn storage_rejects_inconsistent_pieces() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("out");
+    // 10 bytes in 4 byte pieces need 3 hashes
+    let t = test_torrent("out", vec![[0; 20]; 2], Keys::SingleFile { length: 10 });
+    assert!(Storage::open(&t, &path).await.is_err());
+    let mut t = test_torrent("out", vec![[0; 20]; 3], Keys::SingleFile { length: 10 });
+    assert!(Storage::existing(&t, &path).is_ok());
+    t.info.plength = 0;
+    assert!(Storage::existing(&t, &path).is_err());
+}
+
+#[tokio::test]
+async fn storage_rejects_escaping_paths() {
+    let t = test_torrent("dir", vec![[0; 20]], test_files(&[(1, &["..", "evil"])]));
+    let dir = tempfile::tempdir().unwrap();
+    assert!(Storage::open(&t, dir.path()).await.is_err());
+}
@@ -0,0 +1,231 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use tokio::sync::{broadcast, watch};
+
+use crate::extension::{Registry, UtMetadata};
+use crate::peer::Bitfield;
+use crate::pex::UtPex;
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+use crate::tracker::Progress;
+
+/// Settings for how we treat the peers of one torrent.
+#[derive(Debug, Clone)]
+pub struct SwarmConfig {
+    /// How many peers we upload to at once, including the optimistic unchoke.
+    pub upload_slots: usize,
+    /// How many requests we keep outstanding with a peer before we know how fast it is.
+    pub pipeline_depth: usize,
+    /// The most requests we keep outstanding with a peer, however fast it is.
+    pub max_pipeline_depth: usize,
+    /// How many pieces may be partially downloaded at once.
+    pub max_partial_pieces: usize,
+    /// Until we have this many pieces, pieces are picked at random rather than rarest first.
+    pub random_first_pieces: usize,
+    /// A peer that delivers nothing for this long while we wait on it is snubbed, and only gets
+    /// one request at a time.
+    pub snub_timeout: Duration,
+    /// Requests unanswered for this long are given to other peers.
+    pub request_timeout: Duration,
+    /// A peer whose requests time out this many times in a row is disconnected.
+    pub max_timeouts: usize,
+    /// A peer that contributed to this many pieces that failed their hash check is banned.
+    pub max_hash_failures: usize,
+    /// How many peers we try to stay connected to while downloading.
+    pub target_peers: usize,
+}
+
+impl Default for SwarmConfig {
+    fn default() -> Self {
+        Self {
+            upload_slots: 4,
+            pipeline_depth: 5,
+            max_pipeline_depth: 200,
+            max_partial_pieces: 16,
+            random_first_pieces: 4,
+            snub_timeout: Duration::from_secs(20),
+            request_timeout: Duration::from_secs(60),
+            max_timeouts: 3,
+            max_hash_failures: 3,
+            target_peers: 30,
+        }
+    }
+}
+
+/// The state of one torrent that all of its peer connections share: where its data lives, which
+/// pieces of it we have verified, and the extensions we offer its peers.
+pub struct Swarm {
+    pub(crate) info_hash: [u8; 20],
+    pub(crate) storage: Storage,
+    pub(crate) registry: Arc<Registry>,
+    pub(crate) config: SwarmConfig,
+    /// Peer exchange, unless the torrent is private.
+    pub(crate) pex: Option<Arc<UtPex>>,
+    have: RwLock<Bitfield>,
+    /// Every connected peer, by address.
+    pub(crate) peers: Mutex<HashMap<SocketAddr, PeerStats>>,
+    /// Bumped whenever the choker changes who is unchoked, so connections can act on it.
+    pub(crate) rechoked: watch::Sender<()>,
+    /// Every piece that becomes available, so connections can tell their peers about it.
+    pub(crate) haves: broadcast::Sender<usize>,
+    /// The IPs of peers that sent us bad data, which we don't talk to any more.
+    banned: Mutex<HashSet<IpAddr>>,
+    /// Bytes of piece data received from all peers since we started, as reported to trackers.
+    downloaded: AtomicU64,
+    /// Bytes of piece data sent to all peers since we started, as reported to trackers.
+    uploaded: AtomicU64,
+}
+
+/// What the swarm knows about a connected peer: kept up to date by the connection, and read and
+/// decided on by the choker.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PeerStats {
+    /// Bytes of piece data received from the peer.
+    pub(crate) downloaded: u64,
+    /// Bytes of piece data sent to the peer.
+    pub(crate) uploaded: u64,
+    pub(crate) interested: bool,
+    /// Whether the peer has every piece.
+    pub(crate) seed: bool,
+    /// Where the peer accepts connections, if we know.
+    pub(crate) listen_addr: Option<SocketAddr>,
+    /// Whether we connected to the peer, rather than it to us.
+    pub(crate) outgoing: bool,
+    /// Whether the choker lets the peer download from us.
+    pub(crate) unchoked: bool,
+}
+
+impl Swarm {
+    /// `have` holds the pieces of `t` already verified in `storage`.
+    pub fn new(
+        t: &Torrent,
+        storage: Storage,
+        have: Bitfield,
+        config: SwarmConfig,
+    ) -> anyhow::Result<Self> {
+        let mut registry = Registry::default();
+        registry.register(Arc::new(UtMetadata::new(t.info_bytes())));
+        // private torrents only get peers from their trackers
+        let pex = (!t.is_private()).then(|| Arc::new(UtPex::new()));
+        if let Some(pex) = &pex {
+            registry.register(Arc::clone(pex) as _);
+        }
+
+        Ok(Self {
+            info_hash: t.info_hash(),
+            storage,
+            registry: Arc::new(registry),
+            config,
+            pex,
+            have: RwLock::new(have),
+            peers: Mutex::new(HashMap::new()),
+            rechoked: watch::channel(()).0,
+            haves: broadcast::channel(64).0,
+            banned: Mutex::new(HashSet::new()),
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+        })
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    pub fn npieces(&self) -> usize {
+        self.storage.npieces()
+    }
+
+    pub fn has_piece(&self, piece_i: usize) -> bool {
+        self.have.read().expect("not poisoned").has_piece(piece_i)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        (0..self.npieces()).all(|piece_i| self.has_piece(piece_i))
+    }
+
+    /// The number of bytes we still need, as reported to trackers.
+    pub fn left(&self) -> usize {
+        (0..self.npieces())
+            .filter(|&piece_i| !self.has_piece(piece_i))
+            .map(|piece_i| self.storage.piece_length(piece_i))
+            .sum()
+    }
+
+    /// How far along we are, as reported to trackers.
+    pub fn progress(&self) -> Progress {
+        Progress {
+            uploaded: self.uploaded.load(Ordering::Relaxed) as usize,
+            downloaded: self.downloaded.load(Ordering::Relaxed) as usize,
+            left: self.left(),
+        }
+    }
+
+    /// Our bitfield, as advertised to peers.
+    pub fn bitfield(&self) -> Bitfield {
+        self.have.read().expect("not poisoned").clone()
+    }
+
+    /// Records that piece `piece_i` is verified and stored, so it can be served to peers.
+    pub(crate) fn mark_have(&self, piece_i: usize) {
+        self.have.write().expect("not poisoned").set_piece(piece_i);
+        // nobody may be listening, which is fine
+        let _ = self.haves.send(piece_i);
+    }
+
+    /// Stops talking to peers at `ip`.
+    pub(crate) fn ban(&self, ip: IpAddr) {
+        self.banned.lock().expect("not poisoned").insert(ip);
+    }
+
+    pub(crate) fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.lock().expect("not poisoned").contains(&ip)
+    }
+
+    /// The peers other peers told us about since the last call.
+    pub(crate) fn take_discovered(&self) -> Vec<SocketAddr> {
+        self.pex
+            .as_ref()
+            .map_or_else(Vec::new, |pex| pex.take_discovered())
+    }
+
+    /// Unchokes the peer at `addr` right away if fewer peers than we have upload slots for are
+    /// unchoked, rather than leaving it to wait for the next rechoke.
+    pub(crate) fn unchoke_if_free(&self, addr: SocketAddr) {
+        let mut peers = self.peers.lock().expect("not poisoned");
+        let unchoked = peers.values().filter(|stats| stats.unchoked).count();
+        if unchoked < self.config.upload_slots {
+            if let Some(stats) = peers.get_mut(&addr) {
+                stats.unchoked = true;
+            }
+        }
+    }
+
+    /// Counts `bytes` of piece data received from the peer at `addr`.
+    pub(crate) fn record_download(&self, addr: SocketAddr, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        self.update_peer(addr, |stats| stats.downloaded += bytes);
+    }
+
+    /// Counts `bytes` of piece data sent to the peer at `addr`.
+    pub(crate) fn record_upload(&self, addr: SocketAddr, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+        self.update_peer(addr, |stats| stats.uploaded += bytes);
+    }
+
+    /// Updates the stats of the peer at `addr`, if it is still connected.
+    pub(crate) fn update_peer<T>(
+        &self,
+        addr: SocketAddr,
+        update: impl FnOnce(&mut PeerStats) -> T,
+    ) -> Option<T> {
+        self.peers
+            .lock()
+            .expect("not poisoned")
+            .get_mut(&addr)
+            .map(update)
+    }
+}
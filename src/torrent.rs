@@ -1,5 +1,6 @@
 use std::fmt;
 use std::path::Path;
+use std::sync::Arc;
 
 use anyhow::Context;
 use serde::de::{self, Visitor};
@@ -7,7 +8,15 @@ use serde::ser::Serializer;
 use serde::{Deserialize, Deserializer, Serialize};
 use sha1::{Digest, Sha1};
 
-use crate::download::{self, Downloaded};
+use crate::dht::Dht;
+use crate::download;
+use crate::listener::Listener;
+use crate::lsd::Lsd;
+use crate::resume::Resume;
+use crate::seed;
+use crate::storage::Storage;
+use crate::swarm::{Swarm, SwarmConfig};
+use crate::verify::{verify_storage, PieceStatus};
 
 pub fn decode_bencode_value(encoded_value: &str) -> (serde_json::Value, &str) {
     match encoded_value.chars().next() {
@@ -70,21 +79,63 @@ pub fn decode_bencode_value(encoded_value: &str) -> (serde_json::Value, &str) {
 /// A Metainfo files (also known as .torrent files).
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Torrent {
-    /// The URL of the tracker
+    /// The URL of the tracker; empty for trackerless torrents, which rely on the DHT.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
 
+    /// Tiers of tracker URLs (BEP 12), tried in order before falling back to `announce`.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+
+    /// Free-form textual comments of the author.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    /// Name and version of the program used to create the .torrent.
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+
+    /// The creation time of the torrent, in standard UNIX epoch format.
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+
+    /// DHT nodes to bootstrap from, as `(host, port)`, for trackerless torrents (BEP 5).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<(String, u16)>>,
+
     pub info: Info,
+
+    /// The info dictionary exactly as it was hashed, for one fetched from peers: it may hold keys
+    /// `info` does not model, so re-encoding `info` could change the info hash.
+    #[serde(skip)]
+    pub raw_info: Option<Vec<u8>>,
 }
 
 impl Torrent {
     pub fn info_hash(&self) -> [u8; 20] {
-        let info_encoded = serde_bencode::to_bytes(&self.info).expect("re-encode info section");
         let mut hasher = Sha1::new();
-        hasher.update(&info_encoded);
-        hasher
-            .finalize()
-            .try_into()
-            .expect("GenericArray<[u8; 20]>")
+        hasher.update(self.info_bytes());
+        hasher.finalize().into()
+    }
+
+    /// The bencoded info dictionary that [`Torrent::info_hash`] is the hash of.
+    pub fn info_bytes(&self) -> Vec<u8> {
+        match &self.raw_info {
+            Some(raw_info) => raw_info.clone(),
+            None => serde_bencode::to_bytes(&self.info).expect("re-encode info section"),
+        }
     }
 
     pub async fn read(file: impl AsRef<Path>) -> anyhow::Result<Self> {
@@ -93,6 +144,37 @@ impl Torrent {
         Ok(t)
     }
 
+    pub async fn write(&self, file: impl AsRef<Path>) -> anyhow::Result<()> {
+        let dot_torrent = serde_bencode::to_bytes(self).context("encode torrent file")?;
+        tokio::fs::write(file, dot_torrent)
+            .await
+            .context("write torrent file")?;
+        Ok(())
+    }
+
+    /// The tiers of trackers to announce to: `announce-list` if present (BEP 12), otherwise just
+    /// `announce`.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        if tiers.is_empty() && self.announce.is_empty() {
+            Vec::new()
+        } else if tiers.is_empty() {
+            vec![vec![self.announce.clone()]]
+        } else {
+            tiers
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
     pub fn print_tree(&self) {
         match &self.info.keys {
             Keys::SingleFile { .. } => {
@@ -113,8 +195,54 @@ impl Torrent {
         }
     }
 
-    pub async fn download_all(&self) -> anyhow::Result<Downloaded> {
-        download::download_all(self).await
+    /// Downloads the torrent, streaming each verified piece into `output`.
+    ///
+    /// Progress is recorded in a resume file next to `output`, so an interrupted download picks up
+    /// where it left off. Peers are found through the trackers and, if given, the DHT and local
+    /// service discovery, and can connect to us through `listener`.
+    pub async fn download_all_to_file(
+        &self,
+        output: impl AsRef<Path>,
+        dht: Option<&Dht>,
+        listener: Option<&Listener>,
+        lsd: Option<&Lsd>,
+        config: SwarmConfig,
+    ) -> anyhow::Result<()> {
+        let storage = Storage::open(self, output).await?;
+        let mut resume = Resume::load(self, &storage)
+            .await
+            .context("load resume state")?;
+        let swarm = Arc::new(Swarm::new(
+            self,
+            storage,
+            resume.bitfield().clone(),
+            config,
+        )?);
+        download::download_all(self, &swarm, &mut resume, dht, listener, lsd).await
+    }
+
+    /// Seeds the torrent from the data at `data` until interrupted.
+    pub async fn seed_from(
+        &self,
+        data: impl AsRef<Path>,
+        dht: Option<&Dht>,
+        listener: Option<&Listener>,
+        lsd: Option<&Lsd>,
+        config: SwarmConfig,
+    ) -> anyhow::Result<()> {
+        let storage = Storage::existing(self, data)?;
+        let verification = verify_storage(self, &storage)
+            .await
+            .context("verify data to seed")?;
+        anyhow::ensure!(
+            verification
+                .with_status(PieceStatus::Complete)
+                .next()
+                .is_some(),
+            "none of the data is there to seed"
+        );
+        let swarm = Arc::new(Swarm::new(self, storage, verification.bitfield(), config)?);
+        seed::seed(self, &swarm, dht, listener, lsd).await
     }
 }
 
@@ -138,6 +266,10 @@ pub struct Info {
     /// Each of which is the SHA1 hash of the piece at the corresponding index.
     pub pieces: Hashes,
 
+    /// If set to 1, peers may only be obtained from the trackers in the metainfo (BEP 27).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+
     #[serde(flatten)]
     pub keys: Keys,
 }
@@ -187,7 +319,7 @@ impl<'de> Visitor<'de> for HashesVisitor {
     where
         E: de::Error,
     {
-        if v.len() % 20 != 0 {
+        if !v.len().is_multiple_of(20) {
             return Err(E::custom(format!("length is {}", v.len())));
         }
 
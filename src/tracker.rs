@@ -1,9 +1,19 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
 use anyhow::Context;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bytes::ByteBuf;
+
+use self::peers::{Peers, Peers6};
+use self::udp::UdpTracker;
 
-use crate::torrent::Torrent;
+pub mod udp;
 
-use self::peers::Peers;
+/// How long one tracker may take to answer an announce before we move on to the next.
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// Note: the info_hash field is _not_ included.
 #[derive(Debug, Clone, Serialize)]
@@ -29,50 +39,541 @@ pub struct TrackerRequest {
     ///
     /// The compact representation is more commonly used in the wild, the non-compact representation is mostly supported for backward-compatibility.
     pub compact: u8,
+
+    /// Our IPv6 address, so that a tracker we reach over IPv4 can hand us to IPv6 peers too
+    /// (BEP 7).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<String>,
+
+    /// The `tracker id` the tracker sent in an earlier response, if it sent one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trackerid: Option<String>,
+
+    /// Why we announce, unless it is one of the regular announces.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+
+    /// How many peers we would like; the tracker decides if left out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<usize>,
+
+    /// A number only we and the tracker know, so it can tell us apart from others behind the
+    /// same IP, and recognize us if ours changes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<u32>,
+
+    /// Asks for peers without their peer ids. Compact responses never have them anyway.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_peer_id: Option<u8>,
+}
+
+/// What a tracker is told has happened in an announce that isn't a regular one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// The first announce of a session.
+    Started,
+    /// The download finished.
+    Completed,
+    /// We are leaving the swarm.
+    Stopped,
+}
+
+/// How far along we are with a torrent, as reported to trackers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes of piece data sent to peers since we started.
+    pub uploaded: usize,
+    /// Bytes of piece data received from peers since we started.
+    pub downloaded: usize,
+    /// The number of bytes we still need.
+    pub left: usize,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackerResponse {
     /// An integer, indicating how often your client should make a request to the tracker in
     /// seconds.
-    /// You can ignore this value for the purposes of this challenge.
     pub interval: usize,
 
+    /// If present, clients must not reannounce more often than this, in seconds.
+    #[serde(default, rename = "min interval")]
+    pub min_interval: Option<usize>,
+
+    /// A human-readable warning; the announce still succeeded.
+    #[serde(default, rename = "warning message")]
+    pub warning: Option<String>,
+
+    /// A string the tracker wants back as `trackerid` on our next announces.
+    #[serde(default, rename = "tracker id")]
+    pub tracker_id: Option<String>,
+
+    /// The number of seeders in the swarm.
+    #[serde(default)]
+    pub complete: Option<usize>,
+
+    /// The number of leechers in the swarm.
+    #[serde(default)]
+    pub incomplete: Option<usize>,
+
+    /// Our IP address as the tracker sees it (BEP 24).
+    #[serde(
+        default,
+        rename = "external ip",
+        deserialize_with = "deserialize_external_ip"
+    )]
+    pub external_ip: Option<IpAddr>,
+
     /// A string, which contains list of peers that your client can connect to.
     ///
     /// Each peer is represented using 6 bytes.
     /// The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
+    /// Trackers that don't support the compact representation send a list of dictionaries instead.
+    #[serde(default)]
     pub peers: Peers,
+
+    /// IPv6 peers, 18 bytes each: the 16 bytes of the IP address, then the port (BEP 7).
+    ///
+    /// [`TrackerResponse::query_http`] moves them into `peers`.
+    #[serde(default)]
+    pub peers6: Peers6,
+}
+
+/// Why a tracker turned down an announce.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TrackerError {
+    /// The tracker answered with a `failure reason` (or, over UDP, an error action) instead of
+    /// peers.
+    #[error("tracker refused the announce: {0}")]
+    Failure(String),
+}
+
+/// Just the `failure reason` of a response, which comes without any of the other fields.
+#[derive(Deserialize)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    reason: Option<String>,
+}
+
+/// Decodes the 4 or 16 bytes of an IP address; anything else is ignored.
+fn deserialize_external_ip<'de, D>(deserializer: D) -> Result<Option<IpAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let ip = ByteBuf::deserialize(deserializer)?;
+    Ok(match ip.len() {
+        4 => <[u8; 4]>::try_from(&ip[..]).ok().map(IpAddr::from),
+        16 => <[u8; 16]>::try_from(&ip[..]).ok().map(IpAddr::from),
+        _ => None,
+    })
 }
 
 impl TrackerResponse {
-    pub(crate) async fn query(t: &Torrent, info_hash: [u8; 20]) -> anyhow::Result<Self> {
-        let request = TrackerRequest {
-            peer_id: String::from("00112233445566778899"),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: t.length(),
-            compact: 1,
-        };
+    /// Decodes the bencoded response of an HTTP tracker, turning a `failure reason` into
+    /// [`TrackerError::Failure`].
+    pub fn from_bytes(response: &[u8]) -> anyhow::Result<Self> {
+        if let Ok(FailureResponse {
+            reason: Some(reason),
+        }) = serde_bencode::from_bytes(response)
+        {
+            return Err(TrackerError::Failure(reason).into());
+        }
+        serde_bencode::from_bytes(response).context("parse tracker response")
+    }
+
+    /// Sends `request` to the HTTP tracker at `announce`.
+    async fn query_http(
+        announce: &str,
+        info_hash: [u8; 20],
+        request: &TrackerRequest,
+    ) -> anyhow::Result<Self> {
         let url_params =
             serde_urlencoded::to_string(request).context("url-encode tracker parameters")?;
         let tracker_url = format!(
             "{}?{}&info_hash={}",
-            t.announce,
+            announce,
             url_params,
             &urlencode(&info_hash)
         );
 
-        let response = reqwest::get(tracker_url).await.context("query tracker")?;
+        let response = http_client()
+            .get(tracker_url)
+            .send()
+            .await
+            .context("query tracker")?;
         let response = response.bytes().await.context("fetch tracker response")?;
-        let tracker_info: TrackerResponse =
-            serde_bencode::from_bytes(&response).context("parse tracker response")?;
+        let mut tracker_info = TrackerResponse::from_bytes(&response)?;
+        let peers6 = std::mem::take(&mut tracker_info.peers6.0);
+        tracker_info.peers.0.extend(peers6);
 
         Ok(tracker_info)
     }
 }
 
+/// One tracker of a torrent.
+#[derive(Debug)]
+struct Tracker {
+    url: String,
+    /// The client for a `udp://` tracker once we talked to it, kept so that its connection id is
+    /// reused.
+    udp: Option<UdpTracker>,
+}
+
+impl Tracker {
+    fn new(url: String) -> Self {
+        Self { url, udp: None }
+    }
+
+    /// Sends `request` to the tracker: over the UDP tracker protocol for `udp://` URLs,
+    /// everything else over HTTP.
+    async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        request: &TrackerRequest,
+    ) -> anyhow::Result<TrackerResponse> {
+        if !self.url.starts_with("udp://") {
+            return TrackerResponse::query_http(&self.url, info_hash, request).await;
+        }
+        let udp = match &mut self.udp {
+            Some(udp) => udp,
+            None => self.udp.insert(UdpTracker::new(&self.url).await?),
+        };
+        udp.announce(info_hash, request).await
+    }
+}
+
+/// The shortest interval between regular announces we go along with, however short the trackers
+/// ask for.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long telling the trackers that we are leaving may take before we leave anyway.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The trackers of a torrent, grouped in tiers as described by BEP 12, and our session with them.
+///
+/// Trackers within a tier are shuffled once up front and then tried in order; one that responds
+/// is moved to the front of its tier so it is tried first next time. Every tier is announced to,
+/// and the peers from all tiers that responded are merged.
+///
+/// The first announce to each tracker is `started`; [`TrackerTiers::completed`] and
+/// [`TrackerTiers::stopped`] send the other events.
+#[derive(Debug)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<Tracker>>,
+    session: Session,
+}
+
+/// What we keep track of across the announces of one session.
+#[derive(Debug, Clone)]
+struct Session {
+    key: u32,
+    /// How long each tracker may take to answer.
+    timeout: Duration,
+    numwant: Option<usize>,
+    /// The `tracker id` each tracker last sent, to send back on later announces.
+    tracker_ids: HashMap<String, String>,
+    /// The trackers that know we are in the swarm.
+    started: HashSet<String>,
+    last_announce: Option<Instant>,
+    /// How often the trackers want to hear from us, once one has responded.
+    interval: Option<Duration>,
+    /// How often the trackers allow us to announce.
+    min_interval: Duration,
+}
+
+impl Session {
+    fn request(
+        &self,
+        url: &str,
+        progress: Progress,
+        port: u16,
+        event: Option<Event>,
+    ) -> TrackerRequest {
+        TrackerRequest {
+            peer_id: String::from("00112233445566778899"),
+            port,
+            uploaded: progress.uploaded,
+            downloaded: progress.downloaded,
+            left: progress.left,
+            compact: 1,
+            ipv6: local_ipv6().map(|ip| ip.to_string()),
+            trackerid: self.tracker_ids.get(url).cloned(),
+            event,
+            numwant: self.numwant,
+            key: Some(self.key),
+            no_peer_id: Some(1),
+        }
+    }
+}
+
+impl TrackerTiers {
+    pub fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        tiers.retain(|tier| !tier.is_empty());
+        for tier in &mut tiers {
+            shuffle(tier);
+        }
+        Self {
+            tiers: tiers
+                .into_iter()
+                .map(|tier| tier.into_iter().map(Tracker::new).collect())
+                .collect(),
+            session: Session {
+                key: crate::random_u64() as u32,
+                timeout: TRACKER_TIMEOUT,
+                numwant: None,
+                tracker_ids: HashMap::new(),
+                started: HashSet::new(),
+                last_announce: None,
+                interval: None,
+                min_interval: Duration::ZERO,
+            },
+        }
+    }
+
+    /// Asks the trackers for `numwant` peers per announce, rather than as many as they like.
+    pub fn with_numwant(mut self, numwant: usize) -> Self {
+        self.session.numwant = Some(numwant);
+        self
+    }
+
+    /// The URLs of the trackers, tier by tier, in the order they are tried.
+    pub fn tiers(&self) -> Vec<Vec<&str>> {
+        self.tiers
+            .iter()
+            .map(|tier| tier.iter().map(|tracker| tracker.url.as_str()).collect())
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// How often the trackers want to hear from us, once one has responded.
+    pub fn interval(&self) -> Option<Duration> {
+        self.session.interval
+    }
+
+    /// Whether the interval the trackers asked for has passed since we last announced.
+    pub fn announce_due(&self, now: Instant) -> bool {
+        match (self.session.last_announce, self.session.interval) {
+            (Some(last), Some(interval)) => now.duration_since(last) >= interval,
+            _ => false,
+        }
+    }
+
+    /// Whether the trackers' minimum interval has passed since we last announced, so we may
+    /// announce early to find more peers.
+    pub fn may_announce(&self, now: Instant) -> bool {
+        self.session
+            .last_announce
+            .is_none_or(|last| now.duration_since(last) >= self.session.min_interval)
+    }
+
+    /// Announces to every tier that we accept connections on `port`, and how far along we are,
+    /// returning the merged peers and the shortest interval of the tiers that responded.
+    ///
+    /// If no tier responded, the error is that of the first tier, so a [`TrackerError`] can be
+    /// told apart from trackers that could not be reached.
+    pub async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        progress: Progress,
+        port: u16,
+    ) -> anyhow::Result<TrackerResponse> {
+        self.announce_event(info_hash, progress, port, None).await
+    }
+
+    /// Tells every tier that the download finished, as [`TrackerTiers::announce`] does.
+    pub async fn completed(
+        &mut self,
+        info_hash: [u8; 20],
+        progress: Progress,
+        port: u16,
+    ) -> anyhow::Result<TrackerResponse> {
+        self.announce_event(info_hash, progress, port, Some(Event::Completed))
+            .await
+    }
+
+    /// Tells every tracker that knows we are in the swarm that we are leaving, giving up on those
+    /// that don't respond within [`STOP_TIMEOUT`].
+    pub async fn stopped(&mut self, info_hash: [u8; 20], progress: Progress, port: u16) {
+        let started = std::mem::take(&mut self.session.started);
+        let session = &self.session;
+        let stops = self
+            .tiers
+            .iter_mut()
+            .flatten()
+            .filter(|tracker| started.contains(&tracker.url))
+            .map(|tracker| async move {
+                let request = session.request(&tracker.url, progress, port, Some(Event::Stopped));
+                if let Err(e) = tracker.announce(info_hash, &request).await {
+                    eprintln!("tracker {} failed: {e:#}", tracker.url);
+                }
+            });
+        if tokio::time::timeout(STOP_TIMEOUT, futures_util::future::join_all(stops))
+            .await
+            .is_err()
+        {
+            eprintln!("gave up telling the trackers that we are leaving");
+        }
+    }
+
+    async fn announce_event(
+        &mut self,
+        info_hash: [u8; 20],
+        progress: Progress,
+        port: u16,
+        event: Option<Event>,
+    ) -> anyhow::Result<TrackerResponse> {
+        let session = &self.session;
+        let responses = futures_util::future::join_all(
+            self.tiers
+                .iter_mut()
+                .map(|tier| announce_tier(tier, session, info_hash, progress, port, event)),
+        )
+        .await;
+        self.session.last_announce = Some(Instant::now());
+
+        let mut merged: Option<TrackerResponse> = None;
+        let mut error = None;
+        for (tier, response) in self.tiers.iter().zip(responses) {
+            let response = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    error.get_or_insert(e);
+                    continue;
+                }
+            };
+            // the tracker that responded is now at the front of its tier
+            self.session.started.insert(tier[0].url.clone());
+            if let Some(tracker_id) = &response.tracker_id {
+                self.session
+                    .tracker_ids
+                    .insert(tier[0].url.clone(), tracker_id.clone());
+            }
+            match &mut merged {
+                None => merged = Some(response),
+                Some(merged) => {
+                    merged.interval = merged.interval.min(response.interval);
+                    merged.min_interval = merged.min_interval.max(response.min_interval);
+                    merged.complete = merged.complete.max(response.complete);
+                    merged.incomplete = merged.incomplete.max(response.incomplete);
+                    merged.external_ip = merged.external_ip.or(response.external_ip);
+                    for peer in response.peers.0 {
+                        if !merged.peers.0.contains(&peer) {
+                            merged.peers.0.push(peer);
+                        }
+                    }
+                }
+            }
+        }
+        match (merged, error) {
+            (Some(merged), _) => {
+                self.session.interval =
+                    Some(Duration::from_secs(merged.interval as u64).max(MIN_INTERVAL));
+                self.session.min_interval =
+                    Duration::from_secs(merged.min_interval.unwrap_or(0) as u64);
+                Ok(merged)
+            }
+            (None, Some(e)) => Err(e.context("no tracker responded")),
+            (None, None) => anyhow::bail!("no tracker responded"),
+        }
+    }
+}
+
+/// Tries the trackers of `tier` in order, promoting the first one that responds to the front.
+/// Returns the error of the last one if none does.
+///
+/// A regular announce (no `event`) to a tracker that doesn't know we are in the swarm yet is sent
+/// as `started`.
+async fn announce_tier(
+    tier: &mut Vec<Tracker>,
+    session: &Session,
+    info_hash: [u8; 20],
+    progress: Progress,
+    port: u16,
+    event: Option<Event>,
+) -> anyhow::Result<TrackerResponse> {
+    let mut error = None;
+    for i in 0..tier.len() {
+        let url = tier[i].url.clone();
+        let event = event.or((!session.started.contains(&url)).then_some(Event::Started));
+        let request = session.request(&url, progress, port, event);
+        let response = tokio::time::timeout(session.timeout, tier[i].announce(info_hash, &request))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+        match response {
+            Ok(response) => {
+                if let Some(warning) = &response.warning {
+                    eprintln!("tracker {url} warns: {warning}");
+                }
+                let working = tier.remove(i);
+                tier.insert(0, working);
+                return Ok(response);
+            }
+            Err(e) => {
+                eprintln!("tracker {url} failed: {e:#}");
+                error = Some(e.context(format!("announce to {url}")));
+            }
+        }
+    }
+    Err(error.unwrap_or_else(|| anyhow::anyhow!("no trackers in tier")))
+}
+
+fn shuffle<T>(items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        let j = (crate::random_u64() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+/// The client HTTP trackers are announced to with, which gives up on those that don't answer.
+fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(TRACKER_TIMEOUT)
+            .build()
+            .expect("TLS backend is available")
+    })
+}
+
+/// Our IPv6 address as peers elsewhere would see it, if we have one.
+pub(crate) fn local_ipv6() -> Option<Ipv6Addr> {
+    // connecting a UDP socket sends nothing, but picks the address we would send from
+    let socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    let somewhere = Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888);
+    socket.connect((somewhere, 80)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if !ip.is_loopback() && !ip.is_unspecified() => {
+            // link-local addresses are no use beyond our own network segment
+            let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+            (!link_local).then_some(ip)
+        }
+        _ => None,
+    }
+}
+
+/// Decodes compact peers of `len` bytes each: the IP address, then the port. Returns `None` if
+/// there is a partial peer at the end.
+pub(crate) fn decode_compact(compact: &[u8], len: usize) -> Option<Vec<SocketAddr>> {
+    if !compact.len().is_multiple_of(len) {
+        return None;
+    }
+    compact
+        .chunks_exact(len)
+        .map(|peer| {
+            let (ip, port) = peer.split_at(len - 2);
+            let ip: IpAddr = match ip.len() {
+                4 => Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?).into(),
+                16 => Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?).into(),
+                _ => return None,
+            };
+            Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+        })
+        .collect()
+}
+
 pub fn urlencode(t: &[u8; 20]) -> String {
     let mut encoded = String::new();
     for &byte in t {
@@ -85,43 +586,56 @@ pub fn urlencode(t: &[u8; 20]) -> String {
 mod peers {
     use std::{
         fmt,
-        net::{Ipv4Addr, SocketAddrV4},
+        net::{IpAddr, SocketAddr},
     };
 
     use serde::{
-        de::{self, Visitor},
+        de::{self, SeqAccess, Visitor},
         Deserialize, Deserializer, Serialize, Serializer,
     };
 
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
+    use super::decode_compact;
+
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers(pub Vec<SocketAddr>);
     struct PeersVisitor;
 
+    /// A peer in the non-compact representation.
+    #[derive(Deserialize)]
+    struct PeerInfo {
+        /// An IP address or a DNS name.
+        ip: String,
+        port: u16,
+    }
+
     impl<'de> Visitor<'de> for PeersVisitor {
         type Value = Peers;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("6 bytes. the first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.")
+            formatter.write_str("6 bytes per peer: the first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number; or a list of dictionaries with ip and port")
         }
 
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            if v.len() % 6 != 0 {
-                return Err(E::custom(format!("length is {}", v.len())));
-            }
+            decode_compact(v, 6)
+                .map(Peers)
+                .ok_or_else(|| E::custom(format!("length is {}", v.len())))
+        }
 
-            Ok(Peers(
-                v.chunks_exact(6)
-                    .map(|slice_6| {
-                        SocketAddrV4::new(
-                            Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
-                            u16::from_be_bytes([slice_6[4], slice_6[5]]),
-                        )
-                    })
-                    .collect(),
-            ))
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut peers = Vec::new();
+            while let Some(peer) = seq.next_element::<PeerInfo>()? {
+                // peers that only have a DNS name are rare enough not to bother resolving
+                if let Ok(ip) = peer.ip.parse::<IpAddr>() {
+                    peers.push(SocketAddr::new(ip, peer.port));
+                }
+            }
+            Ok(Peers(peers))
         }
     }
 
@@ -130,7 +644,7 @@ mod peers {
         where
             D: Deserializer<'de>,
         {
-            deserializer.deserialize_bytes(PeersVisitor)
+            deserializer.deserialize_any(PeersVisitor)
         }
     }
 
@@ -139,12 +653,286 @@ mod peers {
         where
             S: Serializer,
         {
+            // IPv6 peers have no place in the compact representation; they go in `peers6`
             let mut single_slice = Vec::with_capacity(6 * self.0.len());
             for peer in &self.0 {
-                single_slice.extend(peer.ip().octets());
-                single_slice.extend(peer.port().to_be_bytes());
+                if let IpAddr::V4(ip) = peer.ip() {
+                    single_slice.extend(ip.octets());
+                    single_slice.extend(peer.port().to_be_bytes());
+                }
             }
             serializer.serialize_bytes(&single_slice)
         }
     }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers6(pub Vec<SocketAddr>);
+    struct Peers6Visitor;
+
+    impl<'de> Visitor<'de> for Peers6Visitor {
+        type Value = Peers6;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("18 bytes per peer: the first 16 bytes are the peer's IPv6 address and the last 2 bytes are the peer's port number.")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            decode_compact(v, 18)
+                .map(Peers6)
+                .ok_or_else(|| E::custom(format!("length is {}", v.len())))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Peers6 {
+        fn deserialize<D>(deserializer: D) -> Result<Peers6, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(Peers6Visitor)
+        }
+    }
+}
+
+#[tokio::test]
+async fn tiers_promote_working_tracker() {
+    let progress = Progress {
+        left: 100,
+        ..Progress::default()
+    };
+    let working = format!("udp://{}", udp::stand_in_tracker(0).await);
+    // nothing listens on port 1, so this fails straight away
+    let broken = String::from("http://127.0.0.1:1/announce");
+    let mut tiers = TrackerTiers {
+        tiers: vec![
+            vec![Tracker::new(broken.clone()), Tracker::new(working.clone())],
+            vec![Tracker::new(broken.clone())],
+        ],
+        ..TrackerTiers::new(Vec::new())
+    };
+
+    let response = tiers.announce([1; 20], progress, 6881).await.unwrap();
+    assert_eq!(response.peers.0.len(), 2);
+    assert_eq!(tiers.tiers()[0], vec![working.clone(), broken.clone()]);
+    assert_eq!(tiers.tiers()[1], vec![broken.clone()]);
+    // the UDP tracker's client, and with it the connection id, is kept for the next announce
+    assert!(tiers.tiers[0][0].udp.is_some());
+    tiers.announce([1; 20], progress, 6881).await.unwrap();
+    assert_eq!(tiers.tiers()[0], vec![working, broken.clone()]);
+
+    let mut dead = TrackerTiers::new(vec![vec![broken]]);
+    assert!(dead.announce([1; 20], progress, 6881).await.is_err());
+}
+
+#[test]
+fn tracker_response_peer_formats() {
+    let compact = b"d8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe16:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe2e";
+    let response: TrackerResponse = serde_bencode::from_bytes(compact).unwrap();
+    assert_eq!(
+        response.peers.0,
+        vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+    );
+    assert_eq!(response.peers6.0, vec!["[::1]:6882".parse().unwrap()]);
+
+    let dictionaries = b"d8:intervali1800e5:peersld2:ip8:10.0.0.27:peer id20:000000000000000000004:porti6881eed2:ip3:::14:porti6882eed2:ip11:example.com4:porti1eeee";
+    let response: TrackerResponse = serde_bencode::from_bytes(dictionaries).unwrap();
+    assert_eq!(
+        response.peers.0,
+        vec![
+            "10.0.0.2:6881".parse::<SocketAddr>().unwrap(),
+            "[::1]:6882".parse().unwrap()
+        ]
+    );
+    assert!(response.peers6.0.is_empty());
+
+    let partial = b"d8:intervali1800e6:peers617:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1ae";
+    assert!(serde_bencode::from_bytes::<TrackerResponse>(partial).is_err());
+}
+
+#[test]
+fn tracker_request_includes_ipv6_address() {
+    let mut request = TrackerRequest {
+        peer_id: String::from("00112233445566778899"),
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 100,
+        compact: 1,
+        ipv6: None,
+        trackerid: None,
+        event: None,
+        numwant: None,
+        key: None,
+        no_peer_id: None,
+    };
+    let params = serde_urlencoded::to_string(&request).unwrap();
+    assert!(!params.contains("ipv6"), "{params}");
+    request.ipv6 = Some(String::from("2001:db8::1"));
+    let params = serde_urlencoded::to_string(&request).unwrap();
+    assert!(params.ends_with("&ipv6=2001%3Adb8%3A%3A1"), "{params}");
+}
+
+#[test]
+fn tracker_response_fields_and_failure() {
+    let full = b"d8:completei5e11:external ip4:\xc0\x00\x02\x0110:incompletei3e8:intervali1800e12:min intervali900e5:peers0:10:tracker id3:abc15:warning message4:slowe";
+    let response = TrackerResponse::from_bytes(full).unwrap();
+    assert_eq!(response.interval, 1800);
+    assert_eq!(response.min_interval, Some(900));
+    assert_eq!(response.warning.as_deref(), Some("slow"));
+    assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+    assert_eq!((response.complete, response.incomplete), (Some(5), Some(3)));
+    assert_eq!(response.external_ip, Some(IpAddr::from([192, 0, 2, 1])));
+
+    let failure = b"d14:failure reason17:torrent not founde";
+    let err = TrackerResponse::from_bytes(failure).unwrap_err();
+    assert_eq!(
+        err.downcast_ref(),
+        Some(&TrackerError::Failure(String::from("torrent not found")))
+    );
+}
+
+/// An HTTP tracker on a random local port that answers every announce with the next of
+/// `responses` and passes on the query strings it was sent.
+#[cfg(test)]
+async fn stand_in_http_tracker(
+    responses: Vec<&'static [u8]>,
+) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let (queries, received) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for body in responses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "request cut short");
+                request.extend(&buf[..n]);
+            }
+            let request = String::from_utf8_lossy(&request);
+            let path = request.split(' ').nth(1).unwrap_or_default();
+            let _ = queries.send(path.split_once('?').unwrap_or_default().1.to_string());
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+    });
+    (url, received)
+}
+
+#[tokio::test]
+async fn tiers_send_back_tracker_id_and_report_failures() {
+    let (url, mut queries) = stand_in_http_tracker(vec![
+        b"d8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe110:tracker id5:abc12e",
+        b"d14:failure reason7:go awaye",
+    ])
+    .await;
+    let mut tiers = TrackerTiers::new(vec![vec![url]]);
+    let progress = Progress {
+        left: 100,
+        ..Progress::default()
+    };
+
+    let response = tiers.announce([1; 20], progress, 6881).await.unwrap();
+    assert_eq!(response.peers.0.len(), 1);
+    assert!(!queries.recv().await.unwrap().contains("trackerid"));
+
+    let err = tiers.announce([1; 20], progress, 6881).await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref(),
+        Some(&TrackerError::Failure(String::from("go away")))
+    );
+    assert!(queries.recv().await.unwrap().contains("&trackerid=abc12"));
+}
+
+#[tokio::test]
+async fn tiers_report_the_lifecycle_of_a_download() {
+    let (url, mut queries) = stand_in_http_tracker(vec![
+        b"d8:intervali1800e12:min intervali60e5:peers0:e",
+        b"d8:intervali1800e5:peers0:e",
+        b"d8:intervali1800e5:peers0:e",
+        b"d8:intervali1800e5:peers0:e",
+    ])
+    .await;
+    let mut tiers = TrackerTiers::new(vec![vec![url]]).with_numwant(30);
+    let start = Instant::now();
+    assert!(tiers.may_announce(start));
+    assert!(!tiers.announce_due(start));
+
+    let progress = Progress {
+        uploaded: 0,
+        downloaded: 0,
+        left: 100,
+    };
+    tiers.announce([1; 20], progress, 6881).await.unwrap();
+    let query = queries.recv().await.unwrap();
+    assert!(query.contains("&event=started"), "{query}");
+    assert!(query.contains("&numwant=30&key="), "{query}");
+    assert!(query.contains("&no_peer_id=1"), "{query}");
+    let now = Instant::now();
+    assert!(!tiers.may_announce(now));
+    assert!(tiers.may_announce(now + Duration::from_secs(60)));
+    assert!(!tiers.announce_due(now + Duration::from_secs(60)));
+    assert!(tiers.announce_due(now + Duration::from_secs(1800)));
+
+    let progress = Progress {
+        uploaded: 5,
+        downloaded: 60,
+        left: 40,
+    };
+    tiers.announce([1; 20], progress, 6881).await.unwrap();
+    let query = queries.recv().await.unwrap();
+    assert!(!query.contains("event"), "{query}");
+    assert!(
+        query.contains("uploaded=5&downloaded=60&left=40"),
+        "{query}"
+    );
+
+    let progress = Progress {
+        uploaded: 5,
+        downloaded: 100,
+        left: 0,
+    };
+    tiers.completed([1; 20], progress, 6881).await.unwrap();
+    let query = queries.recv().await.unwrap();
+    assert!(query.contains("left=0"), "{query}");
+    assert!(query.contains("&event=completed"), "{query}");
+
+    tiers.stopped([1; 20], progress, 6881).await;
+    let query = queries.recv().await.unwrap();
+    assert!(query.contains("&event=stopped"), "{query}");
+    // only trackers that know about us are told we are leaving
+    tiers.stopped([1; 20], progress, 6881).await;
+    assert!(queries.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn tiers_move_on_from_trackers_that_hang() {
+    // accepts connections, but never answers
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hanging = format!("http://{}/announce", silent.local_addr().unwrap());
+    let working = format!("udp://{}", udp::stand_in_tracker(0).await);
+    let mut tiers = TrackerTiers {
+        tiers: vec![vec![
+            Tracker::new(hanging.clone()),
+            Tracker::new(working.clone()),
+        ]],
+        ..TrackerTiers::new(Vec::new())
+    };
+    tiers.session.timeout = Duration::from_millis(200);
+
+    let response = tiers
+        .announce([1; 20], Progress::default(), 6881)
+        .await
+        .unwrap();
+    assert_eq!(response.peers.0.len(), 2);
+    assert_eq!(tiers.tiers()[0], vec![working, hanging]);
 }
@@ -0,0 +1,419 @@
+//! The UDP tracker protocol (BEP 15).
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::net::UdpSocket;
+
+use super::{peers::Peers, Event, TrackerError, TrackerRequest, TrackerResponse};
+
+/// Magic constant that identifies a connect request.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// A connection id may be used for one minute after it was received.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+/// Large enough for an announce response with a few hundred peers.
+const MAX_PACKET: usize = 1 << 16;
+
+/// Swarm statistics for one info hash, as returned by a scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scrape {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+/// A client for one UDP tracker.
+///
+/// The connection id is cached and reused for as long as the tracker allows, so keep the client
+/// around across announces.
+#[derive(Debug)]
+pub struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+
+    /// Lets the tracker recognise us across announces even if our IP address changes.
+    key: u32,
+
+    /// Wait this long for the first response, doubling after every retransmission.
+    pub base_timeout: Duration,
+
+    /// Give up after this many retransmissions. BEP 15 goes up to 8, which is hours of waiting on
+    /// a tracker that is gone.
+    pub max_retries: u32,
+}
+
+impl UdpTracker {
+    /// Resolves the tracker at `url` (`udp://host:port[/...]`).
+    pub async fn new(url: &str) -> anyhow::Result<Self> {
+        let url = reqwest::Url::parse(url).context("parse tracker URL")?;
+        anyhow::ensure!(url.scheme() == "udp", "not a UDP tracker URL");
+        let host = url.host_str().context("tracker URL has no host")?;
+        let port = url.port().context("tracker URL has no port")?;
+        let addr = tokio::net::lookup_host((host, port))
+            .await
+            .context("resolve tracker host")?
+            .next()
+            .context("tracker host has no addresses")?;
+        Self::connect_to(addr).await
+    }
+
+    pub async fn connect_to(addr: SocketAddr) -> anyhow::Result<Self> {
+        let bind: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind).await.context("bind UDP socket")?;
+        socket
+            .connect(addr)
+            .await
+            .context("connect UDP socket to tracker")?;
+        Ok(Self {
+            socket,
+            connection: None,
+            key: crate::random_u64() as u32,
+            base_timeout: Duration::from_secs(15),
+            max_retries: 2,
+        })
+    }
+
+    pub async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        request: &TrackerRequest,
+    ) -> anyhow::Result<TrackerResponse> {
+        let peer_id: [u8; 20] = request
+            .peer_id
+            .as_bytes()
+            .try_into()
+            .context("peer id must be 20 bytes")?;
+
+        let key = request.key.unwrap_or(self.key);
+        let event: u32 = match request.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        };
+        // -1 leaves it to the tracker
+        let num_want = request
+            .numwant
+            .map_or(-1, |numwant| i32::try_from(numwant).unwrap_or(i32::MAX));
+        let response = self
+            .transact(ACTION_ANNOUNCE, |body| {
+                body.extend(info_hash);
+                body.extend(peer_id);
+                body.extend((request.downloaded as u64).to_be_bytes());
+                body.extend((request.left as u64).to_be_bytes());
+                body.extend((request.uploaded as u64).to_be_bytes());
+                body.extend(event.to_be_bytes());
+                body.extend(0u32.to_be_bytes()); // ip: the sender's
+                body.extend(key.to_be_bytes());
+                body.extend(num_want.to_be_bytes());
+                body.extend(request.port.to_be_bytes());
+            })
+            .await?;
+
+        anyhow::ensure!(response.len() >= 12, "announce response too short");
+        let interval = u32::from_be_bytes(response[..4].try_into().expect("4 bytes"));
+        // a tracker reached over IPv6 sends IPv6 peers
+        let len = match self.socket.peer_addr() {
+            Ok(SocketAddr::V6(_)) => 18,
+            _ => 6,
+        };
+        let peers = super::decode_compact(&response[12..], len)
+            .context("announce response has a partial peer")?;
+
+        let field = |at: usize| {
+            u32::from_be_bytes(response[at..at + 4].try_into().expect("4 bytes")) as usize
+        };
+        Ok(TrackerResponse {
+            interval: interval as usize,
+            min_interval: None,
+            warning: None,
+            tracker_id: None,
+            complete: Some(field(8)),
+            incomplete: Some(field(4)),
+            external_ip: None,
+            peers: Peers(peers),
+            peers6: Default::default(),
+        })
+    }
+
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<Scrape>> {
+        let response = self
+            .transact(ACTION_SCRAPE, |body| {
+                for info_hash in info_hashes {
+                    body.extend(info_hash);
+                }
+            })
+            .await?;
+
+        anyhow::ensure!(
+            response.len() >= 12 * info_hashes.len(),
+            "scrape response too short"
+        );
+        Ok(response
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|stats| {
+                let field = |i: usize| u32::from_be_bytes(stats[i..][..4].try_into().expect("4"));
+                Scrape {
+                    seeders: field(0),
+                    completed: field(4),
+                    leechers: field(8),
+                }
+            })
+            .collect())
+    }
+
+    /// The current connection id, fetching a new one if we have none or it has expired.
+    async fn connection_id(&mut self) -> anyhow::Result<u64> {
+        if let Some((id, obtained)) = self.connection {
+            if obtained.elapsed() < CONNECTION_ID_TTL {
+                return Ok(id);
+            }
+        }
+
+        let mut request = Vec::with_capacity(16);
+        request.extend(PROTOCOL_ID.to_be_bytes());
+        request.extend(ACTION_CONNECT.to_be_bytes());
+        let response = self.exchange(ACTION_CONNECT, request, None).await?;
+        anyhow::ensure!(response.len() >= 8, "connect response too short");
+        let id = u64::from_be_bytes(response[..8].try_into().expect("8 bytes"));
+        self.connection = Some((id, Instant::now()));
+        Ok(id)
+    }
+
+    /// Sends a request for `action` with the body written by `body`, and returns the body of the
+    /// response, (re)connecting as needed.
+    async fn transact(
+        &mut self,
+        action: u32,
+        body: impl Fn(&mut Vec<u8>),
+    ) -> anyhow::Result<Vec<u8>> {
+        loop {
+            let connection_id = self.connection_id().await?;
+            let expires = self
+                .connection
+                .map(|(_, obtained)| obtained + CONNECTION_ID_TTL);
+            let mut request = Vec::with_capacity(98);
+            request.extend(connection_id.to_be_bytes());
+            request.extend(action.to_be_bytes());
+            body(&mut request);
+
+            match self.exchange(action, request, expires).await {
+                Err(e) if e.is::<ConnectionExpired>() => {
+                    // retransmitting would use a stale connection id
+                    self.connection = None;
+                    continue;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends `request` until a matching response arrives, backing off 15 * 2^n seconds between
+    /// retransmissions as the BEP describes. Returns the response with its header stripped.
+    ///
+    /// If `connection_deadline` passes while waiting, gives up with [`ConnectionExpired`] so the
+    /// caller can fetch a new connection id first.
+    async fn exchange(
+        &mut self,
+        action: u32,
+        mut request: Vec<u8>,
+        connection_deadline: Option<Instant>,
+    ) -> anyhow::Result<Vec<u8>> {
+        // the transaction id always follows the 8-byte connection id/protocol id and the action
+        let transaction_id = crate::random_u64() as u32;
+        request.splice(12..12, transaction_id.to_be_bytes());
+
+        let mut buf = vec![0; MAX_PACKET];
+        for n in 0..=self.max_retries {
+            if connection_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(ConnectionExpired.into());
+            }
+            self.socket
+                .send(&request)
+                .await
+                .context("send to tracker")?;
+
+            let timeout = self.base_timeout * 2u32.pow(n);
+            let deadline = tokio::time::Instant::now() + timeout;
+            loop {
+                let len = match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await
+                {
+                    Ok(len) => len.context("receive from tracker")?,
+                    Err(_) => break,
+                };
+                let response = &buf[..len];
+                if response.len() < 8 {
+                    continue;
+                }
+                let got_action = u32::from_be_bytes(response[..4].try_into().expect("4 bytes"));
+                let got_transaction = u32::from_be_bytes(response[4..8].try_into().expect("4"));
+                if got_transaction != transaction_id {
+                    // a late response to an earlier request
+                    continue;
+                }
+                if got_action == ACTION_ERROR {
+                    let message = String::from_utf8_lossy(&response[8..]);
+                    return Err(TrackerError::Failure(message.into_owned()).into());
+                }
+                anyhow::ensure!(
+                    got_action == action,
+                    "tracker answered action {action} with action {got_action}"
+                );
+                return Ok(response[8..].to_vec());
+            }
+        }
+        anyhow::bail!("tracker did not respond")
+    }
+}
+
+#[derive(Debug)]
+struct ConnectionExpired;
+
+impl std::fmt::Display for ConnectionExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("connection id expired")
+    }
+}
+
+impl std::error::Error for ConnectionExpired {}
+
+#[cfg(test)]
+pub(crate) async fn stand_in_tracker(drop_first: usize) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0; MAX_PACKET];
+        let mut dropped = 0;
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            if dropped < drop_first {
+                dropped += 1;
+                continue;
+            }
+            let request = &buf[..len];
+            let action = u32::from_be_bytes(request[8..12].try_into().unwrap());
+            let transaction = &request[12..16];
+            let mut response = Vec::new();
+            response.extend(action.to_be_bytes());
+            response.extend(transaction);
+            match action {
+                ACTION_CONNECT => {
+                    assert_eq!(&request[..8], PROTOCOL_ID.to_be_bytes());
+                    response.extend(42u64.to_be_bytes());
+                }
+                ACTION_ANNOUNCE if request[..8] != 42u64.to_be_bytes() => {
+                    response.clear();
+                    response.extend(ACTION_ERROR.to_be_bytes());
+                    response.extend(transaction);
+                    response.extend(b"bad connection id");
+                }
+                ACTION_ANNOUNCE => {
+                    assert_eq!(len, 98);
+                    response.extend(1800u32.to_be_bytes());
+                    response.extend(1u32.to_be_bytes());
+                    response.extend(2u32.to_be_bytes());
+                    response.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+                    response.extend([10, 0, 0, 2, 0x1a, 0xe2]);
+                }
+                ACTION_SCRAPE => {
+                    for _ in request[16..].chunks_exact(20) {
+                        response.extend(5u32.to_be_bytes());
+                        response.extend(6u32.to_be_bytes());
+                        response.extend(7u32.to_be_bytes());
+                    }
+                }
+                _ => unreachable!("unknown action"),
+            }
+            socket.send_to(&response, from).await.unwrap();
+        }
+    });
+    addr
+}
+
+#[cfg(test)]
+fn test_request() -> TrackerRequest {
+    TrackerRequest {
+        peer_id: String::from("00112233445566778899"),
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 100,
+        compact: 1,
+        ipv6: None,
+        trackerid: None,
+        event: None,
+        numwant: None,
+        key: None,
+        no_peer_id: None,
+    }
+}
+
+#[tokio::test]
+async fn udp_announce_and_scrape() {
+    let addr = stand_in_tracker(0).await;
+    let mut tracker = UdpTracker::connect_to(addr).await.unwrap();
+
+    let response = tracker.announce([1; 20], &test_request()).await.unwrap();
+    assert_eq!(response.interval, 1800);
+    assert_eq!((response.complete, response.incomplete), (Some(2), Some(1)));
+    assert_eq!(
+        response.peers.0,
+        vec![
+            "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "10.0.0.2:6882".parse().unwrap()
+        ]
+    );
+
+    let scrape = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+    assert_eq!(scrape.len(), 2);
+    assert_eq!(
+        scrape[1],
+        Scrape {
+            seeders: 5,
+            completed: 6,
+            leechers: 7
+        }
+    );
+}
+
+#[tokio::test]
+async fn udp_retransmits_and_reports_errors() {
+    // the first two packets (a connect and its first retransmission) go unanswered
+    let addr = stand_in_tracker(2).await;
+    let mut tracker = UdpTracker::connect_to(addr).await.unwrap();
+    tracker.base_timeout = Duration::from_millis(20);
+    let response = tracker.announce([1; 20], &test_request()).await.unwrap();
+    assert_eq!(response.peers.0.len(), 2);
+
+    // a stale connection id is rejected by the tracker, which surfaces as an error
+    tracker.connection = Some((7, Instant::now()));
+    let err = tracker
+        .announce([1; 20], &test_request())
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err.downcast_ref(), Some(TrackerError::Failure(message)) if message.contains("bad connection id")),
+        "{err}"
+    );
+
+    // and a tracker that never answers is given up on
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut tracker = UdpTracker::connect_to(silent.local_addr().unwrap())
+        .await
+        .unwrap();
+    tracker.base_timeout = Duration::from_millis(5);
+    tracker.max_retries = 2;
+    assert!(tracker.announce([1; 20], &test_request()).await.is_err());
+}
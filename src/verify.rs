@@ -0,0 +1,117 @@
+use std::path::Path;
+
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+
+use crate::{peer::Bitfield, storage::Storage, torrent::Torrent};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    /// The data on disk matches the piece hash.
+    Complete,
+    /// The data is all there, but does not match the piece hash.
+    Corrupt,
+    /// Some file the piece lives in is missing or too short.
+    Missing,
+}
+
+/// The result of checking existing data against the piece hashes of a torrent.
+#[derive(Debug, Clone)]
+pub struct Verification {
+    pub pieces: Vec<PieceStatus>,
+}
+
+impl Verification {
+    /// The pieces that are complete, in the format of a peer `Bitfield` message.
+    pub fn bitfield(&self) -> Bitfield {
+        let mut bitfield = Bitfield::new(self.pieces.len());
+        for piece_i in self.with_status(PieceStatus::Complete) {
+            bitfield.set_piece(piece_i);
+        }
+        bitfield
+    }
+
+    pub fn with_status(&self, status: PieceStatus) -> impl Iterator<Item = usize> + '_ {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter_map(move |(piece_i, &s)| (s == status).then_some(piece_i))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|&s| s == PieceStatus::Complete)
+    }
+}
+
+/// Hashes every piece of `t` stored at `data` (laid out as for a download to that path) and
+/// compares it against the torrent's piece hashes. Nothing on disk is modified.
+pub async fn verify(t: &Torrent, data: impl AsRef<Path>) -> anyhow::Result<Verification> {
+    let storage = Storage::existing(t, data)?;
+    verify_storage(t, &storage).await
+}
+
+pub async fn verify_storage(t: &Torrent, storage: &Storage) -> anyhow::Result<Verification> {
+    let mut on_disk = Vec::with_capacity(storage.files().len());
+    for file in storage.files() {
+        let length = match tokio::fs::metadata(&file.path).await {
+            Ok(metadata) if metadata.is_file() => metadata.len() as usize,
+            Ok(_) => 0,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e).with_context(|| format!("stat {}", file.path.display())),
+        };
+        on_disk.push(length);
+    }
+
+    let mut pieces = Vec::with_capacity(t.info.pieces.0.len());
+    for (piece_i, expected) in t.info.pieces.0.iter().enumerate() {
+        let length = storage.piece_length(piece_i);
+        let present = length > 0
+            && storage
+                .spans(piece_i * t.info.plength, length)
+                .all(|span| on_disk[span.file_i] >= span.offset + span.length);
+        if !present {
+            pieces.push(PieceStatus::Missing);
+            continue;
+        }
+
+        let data = storage.read_piece(piece_i).await?;
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        let hash: [u8; 20] = hasher.finalize().into();
+        pieces.push(if hash == *expected {
+            PieceStatus::Complete
+        } else {
+            PieceStatus::Corrupt
+        });
+    }
+
+    Ok(Verification { pieces })
+}
+
+#[tokio::test]
+async fn verify_reports_piece_status() {
+    use crate::storage::{test_files, test_torrent};
+
+    let hash = |data: &[u8]| -> [u8; 20] { Sha1::digest(data).into() };
+    let t = test_torrent(
+        "dir",
+        vec![hash(b"abcd"), hash(b"efgh"), hash(b"ij")],
+        test_files(&[(6, &["a"]), (4, &["b"])]),
+    );
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("dir")).unwrap();
+    std::fs::write(dir.path().join("dir").join("a"), b"abcdeX").unwrap();
+    std::fs::write(dir.path().join("dir").join("b"), b"gh").unwrap();
+
+    let v = verify(&t, dir.path()).await.unwrap();
+    assert_eq!(
+        v.pieces,
+        vec![
+            PieceStatus::Complete,
+            PieceStatus::Corrupt,
+            PieceStatus::Missing
+        ]
+    );
+    assert_eq!(v.bitfield().as_bytes(), &[0b10000000]);
+    assert!(!v.is_complete());
+}